@@ -0,0 +1,51 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Stamp the build with the git commit it was built from, for `/api/version`.
+//!
+//! `CAVALIER_GIT_COMMIT` wins when it is set, since docker builds have no git to ask.
+
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=CAVALIER_GIT_COMMIT");
+    // the commit changes whenever HEAD or a branch moves
+    for path in ["../.git/HEAD", "../.git/refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+
+    let commit = std::env::var("CAVALIER_GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(git_commit)
+        .unwrap_or_else(|| String::from("unknown"));
+    println!("cargo:rustc-env=CAVALIER_GIT_COMMIT={commit}");
+}
+
+fn git_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let commit = String::from_utf8(output.stdout).ok()?;
+    Some(commit.trim().to_string())
+}
@@ -0,0 +1,140 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Pseudonymous authors
+//!
+//! Every session is given a random pseudonym, like "Quiet Heron 3", the first time it makes a
+//! message. It is kept in the session, so it stays the same until the session expires or is
+//! replaced through `/api/session/new`. Users can also choose a nickname to show instead, through
+//! `/api/session/author`.
+//!
+//! A message keeps the author it was started with, so changing nickname doesn't rename messages
+//! that were already typed.
+
+use crate::logging;
+use axum::{Json, http::StatusCode, response::IntoResponse};
+use cavalier_protocol::{Author, valid_nickname};
+use rand::{Rng, seq::IndexedRandom};
+use serde::Deserialize;
+use tower_sessions::{Session, session};
+
+/// Where the author is kept in the session
+const AUTHOR_KEY: &str = "author";
+
+const ADJECTIVES: [&str; 24] = [
+    "Brisk", "Quiet", "Bold", "Sly", "Merry", "Gentle", "Swift", "Lucky", "Clever", "Wild", "Calm",
+    "Eager", "Fuzzy", "Grumpy", "Jolly", "Nimble", "Proud", "Sleepy", "Sunny", "Witty", "Zesty",
+    "Curious", "Daring", "Humble",
+];
+
+const ANIMALS: [&str; 24] = [
+    "Otter", "Heron", "Fox", "Badger", "Lynx", "Moose", "Owl", "Panda", "Raven", "Seal", "Tapir",
+    "Walrus", "Yak", "Zebra", "Bison", "Crane", "Dingo", "Ferret", "Gecko", "Ibis", "Koala",
+    "Lemur", "Marmot", "Newt",
+];
+
+fn random_pseudonym() -> String {
+    let mut rng = rand::rng();
+    format!(
+        "{} {} {}",
+        ADJECTIVES.choose(&mut rng).unwrap_or(&"Anonymous"),
+        ANIMALS.choose(&mut rng).unwrap_or(&"Cavalier"),
+        rng.random_range(1..100)
+    )
+}
+
+/// The session's author, giving it a pseudonym if it doesn't have one yet
+pub async fn session_author(session: &Session) -> Result<Author, session::Error> {
+    if let Some(author) = session.get::<Author>(AUTHOR_KEY).await? {
+        return Ok(author);
+    }
+    let author = Author {
+        pseudonym: random_pseudonym(),
+        nickname: None,
+    };
+    session.insert(AUTHOR_KEY, &author).await?;
+    Ok(author)
+}
+
+/*******************\
+* Author JSON APIs *
+\*******************/
+
+/// Get who the session types as
+pub async fn author_get_handler(session: Session) -> impl IntoResponse {
+    logging::record_session(&session);
+    match session_author(&session).await {
+        Ok(author) => (StatusCode::OK, Json(author)).into_response(),
+        Err(e) => {
+            tracing::error!(error = %e, "could not get session author");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("Could not access session, try again."),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Body of a nickname change. A `null` nickname goes back to the pseudonym.
+#[derive(Deserialize)]
+pub struct NicknameChange {
+    nickname: Option<String>,
+}
+
+/// Choose the nickname the session's new messages are shown with
+pub async fn author_nickname_handler(
+    session: Session,
+    Json(change): Json<NicknameChange>,
+) -> impl IntoResponse {
+    logging::record_session(&session);
+    if change
+        .nickname
+        .as_deref()
+        .is_some_and(|nickname| !valid_nickname(nickname))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json("Nicknames must be 1-32 characters, without surrounding spaces"),
+        )
+            .into_response();
+    }
+    let author = match session_author(&session).await {
+        Ok(author) => Author {
+            nickname: change.nickname,
+            ..author
+        },
+        Err(e) => {
+            tracing::error!(error = %e, "could not get session author");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("Could not access session, try again."),
+            )
+                .into_response();
+        }
+    };
+    if let Err(e) = session.insert(AUTHOR_KEY, &author).await {
+        tracing::error!(error = %e, "could not set session author");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json("Could not access session, try again."),
+        )
+            .into_response();
+    }
+    (StatusCode::OK, Json(author)).into_response()
+}
@@ -0,0 +1,417 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Runtime configuration
+//!
+//! Every setting has a default, can be set in a TOML file passed with `--config`, and can be
+//! overridden with a `CAVALIER_*` environment variable or a command line flag. Flags beat
+//! environment variables, which beat the file, which beats the defaults. `--print-config` prints
+//! the settings in effect as TOML, which is also the file's format.
+
+use crate::limits::{Rate, RateLimitConfig};
+use crate::logging::LogFormat;
+use crate::rooms::WELCOME_TEXT;
+use crate::store::StoreLimits;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The most keystrokes or events a room can buffer for each socket. Broadcast channels allocate
+/// every slot up front, and tokio refuses capacities past `usize::MAX / 2` outright.
+const MAX_CHANNEL_CAPACITY: usize = 1_000_000;
+
+/// Everything the server can be configured with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The address to listen on
+    pub bind: SocketAddr,
+    /// How many reverse proxies are in front of the server. Each appends the address it saw to
+    /// `X-Forwarded-For`, which is where the client's address is read from when this isn't 0.
+    pub proxy_hops: usize,
+    /// The address to serve Prometheus metrics on, away from clients. They are served next to the
+    /// chat at `/api/metrics` when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics_bind: Option<SocketAddr>,
+    /// Where to keep messages in SQLite. They are kept in RAM when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sqlite_path: Option<PathBuf>,
+    /// The first message of every new room
+    pub welcome_text: String,
+    /// Sessions are forgotten after this long without a request
+    pub session_expiry_secs: u64,
+    /// How often websockets are pinged to keep them open
+    pub ping_interval_secs: u64,
+    /// Messages left open this long without a keystroke are finished
+    pub message_idle_secs: u64,
+    /// How many keystrokes or events a room buffers for each socket before it falls behind
+    pub channel_capacity: usize,
+    /// How long to wait for sockets to close when shutting down before exiting anyway
+    pub drain_deadline_secs: u64,
+    /// Also serve `/ws/events` and `/ws/key`, the separate sockets clients used before `/ws`
+    pub legacy_sockets: bool,
+    /// `text` for people, or `json` for log pipelines. `RUST_LOG` picks what is logged.
+    pub log_format: LogFormat,
+    pub store: StoreLimits,
+    pub rate_limits: RateLimitConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            #[cfg(not(debug_assertions))]
+            bind: SocketAddr::from(([0, 0, 0, 0], 80)),
+            #[cfg(debug_assertions)]
+            bind: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            proxy_hops: 0,
+            metrics_bind: None,
+            sqlite_path: None,
+            welcome_text: String::from(WELCOME_TEXT),
+            session_expiry_secs: 10 * 60,
+            ping_interval_secs: 10,
+            message_idle_secs: 120,
+            channel_capacity: 10_000,
+            drain_deadline_secs: 10,
+            legacy_sockets: true,
+            log_format: LogFormat::Text,
+            store: StoreLimits::default(),
+            rate_limits: RateLimitConfig::default(),
+        }
+    }
+}
+
+/// Command line flags, each of which can also be set with its environment variable
+#[derive(Parser, Debug)]
+#[command(version, about = "Extralive chat server")]
+pub struct Args {
+    /// TOML file to read settings from
+    #[arg(long, env = "CAVALIER_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Print the settings in effect as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    #[arg(long, env = "CAVALIER_BIND")]
+    bind: Option<SocketAddr>,
+    #[arg(long, env = "CAVALIER_PROXY_HOPS")]
+    proxy_hops: Option<usize>,
+    #[arg(long, env = "CAVALIER_METRICS_BIND")]
+    metrics_bind: Option<SocketAddr>,
+    #[arg(long, env = "CAVALIER_SQLITE_PATH")]
+    sqlite_path: Option<PathBuf>,
+    #[arg(long, env = "CAVALIER_WELCOME_TEXT")]
+    welcome_text: Option<String>,
+    #[arg(long, env = "CAVALIER_SESSION_EXPIRY_SECS")]
+    session_expiry_secs: Option<u64>,
+    #[arg(long, env = "CAVALIER_PING_INTERVAL_SECS")]
+    ping_interval_secs: Option<u64>,
+    #[arg(long, env = "CAVALIER_MESSAGE_IDLE_SECS")]
+    message_idle_secs: Option<u64>,
+    #[arg(long, env = "CAVALIER_CHANNEL_CAPACITY")]
+    channel_capacity: Option<usize>,
+    #[arg(long, env = "CAVALIER_DRAIN_DEADLINE_SECS")]
+    drain_deadline_secs: Option<u64>,
+    #[arg(long, env = "CAVALIER_LEGACY_SOCKETS")]
+    legacy_sockets: Option<bool>,
+    #[arg(long, env = "CAVALIER_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+
+    #[arg(long, env = "CAVALIER_MAX_MESSAGE_CHARS")]
+    max_message_chars: Option<usize>,
+    #[arg(long, env = "CAVALIER_MAX_MESSAGES")]
+    max_messages: Option<usize>,
+    #[arg(long, env = "CAVALIER_MAX_ROOM_BYTES")]
+    max_room_bytes: Option<usize>,
+
+    #[arg(long, env = "CAVALIER_MESSAGE_SESSION_RATE")]
+    message_session_rate: Option<f64>,
+    #[arg(long, env = "CAVALIER_MESSAGE_SESSION_BURST")]
+    message_session_burst: Option<f64>,
+    #[arg(long, env = "CAVALIER_MESSAGE_IP_RATE")]
+    message_ip_rate: Option<f64>,
+    #[arg(long, env = "CAVALIER_MESSAGE_IP_BURST")]
+    message_ip_burst: Option<f64>,
+    #[arg(long, env = "CAVALIER_KEY_SESSION_RATE")]
+    key_session_rate: Option<f64>,
+    #[arg(long, env = "CAVALIER_KEY_SESSION_BURST")]
+    key_session_burst: Option<f64>,
+    #[arg(long, env = "CAVALIER_KEY_IP_RATE")]
+    key_ip_rate: Option<f64>,
+    #[arg(long, env = "CAVALIER_KEY_IP_BURST")]
+    key_ip_burst: Option<f64>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read
+    Read(PathBuf, std::io::Error),
+    /// The config file is not valid TOML for a `Config`
+    Parse(toml::de::Error),
+    /// A setting is out of range
+    Invalid(&'static str),
+    /// A setting is larger than the server can handle
+    TooLarge(&'static str, usize),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read {}: {e}", path.display()),
+            ConfigError::Parse(e) => write!(f, "invalid config file: {e}"),
+            ConfigError::Invalid(setting) => write!(f, "{setting} must be positive"),
+            ConfigError::TooLarge(setting, max) => write!(f, "{setting} must be at most {max}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// The config file named by `args`, if any, overridden by the flags and environment variables
+    pub fn load(args: &Args) -> Result<Config, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let toml = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError::Read(path.clone(), e))?;
+                Config::from_toml(&toml)?
+            }
+            None => Config::default(),
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse a config file. Settings it leaves out keep their defaults.
+    pub fn from_toml(toml: &str) -> Result<Config, ConfigError> {
+        toml::from_str(toml).map_err(ConfigError::Parse)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("Config is always valid TOML")
+    }
+
+    /// Override settings with the ones given as flags or environment variables
+    fn apply(&mut self, args: &Args) {
+        fn set<T: Clone>(setting: &mut T, arg: &Option<T>) {
+            if let Some(value) = arg {
+                *setting = value.clone();
+            }
+        }
+
+        set(&mut self.bind, &args.bind);
+        set(&mut self.proxy_hops, &args.proxy_hops);
+        if args.metrics_bind.is_some() {
+            self.metrics_bind = args.metrics_bind;
+        }
+        if args.sqlite_path.is_some() {
+            self.sqlite_path = args.sqlite_path.clone();
+        }
+        set(&mut self.welcome_text, &args.welcome_text);
+        set(&mut self.session_expiry_secs, &args.session_expiry_secs);
+        set(&mut self.ping_interval_secs, &args.ping_interval_secs);
+        set(&mut self.message_idle_secs, &args.message_idle_secs);
+        set(&mut self.channel_capacity, &args.channel_capacity);
+        set(&mut self.drain_deadline_secs, &args.drain_deadline_secs);
+        set(&mut self.legacy_sockets, &args.legacy_sockets);
+        set(&mut self.log_format, &args.log_format);
+
+        set(&mut self.store.max_message_chars, &args.max_message_chars);
+        set(&mut self.store.max_messages, &args.max_messages);
+        set(&mut self.store.max_room_bytes, &args.max_room_bytes);
+
+        let limits = &mut self.rate_limits;
+        set(
+            &mut limits.message_session.per_sec,
+            &args.message_session_rate,
+        );
+        set(
+            &mut limits.message_session.burst,
+            &args.message_session_burst,
+        );
+        set(&mut limits.message_ip.per_sec, &args.message_ip_rate);
+        set(&mut limits.message_ip.burst, &args.message_ip_burst);
+        set(&mut limits.key_session.per_sec, &args.key_session_rate);
+        set(&mut limits.key_session.burst, &args.key_session_burst);
+        set(&mut limits.key_ip.per_sec, &args.key_ip_rate);
+        set(&mut limits.key_ip.burst, &args.key_ip_burst);
+    }
+
+    /// Reject settings that would stop the server from working at all
+    fn validate(&self) -> Result<(), ConfigError> {
+        fn positive(value: u64, setting: &'static str) -> Result<(), ConfigError> {
+            if value == 0 {
+                return Err(ConfigError::Invalid(setting));
+            }
+            Ok(())
+        }
+        // a bucket must hold at least one token, or nothing is ever allowed
+        fn rate(rate: &Rate, setting: &'static str) -> Result<(), ConfigError> {
+            if !(rate.per_sec > 0.0 && rate.per_sec.is_finite() && rate.burst >= 1.0) {
+                return Err(ConfigError::Invalid(setting));
+            }
+            Ok(())
+        }
+
+        positive(self.session_expiry_secs, "session_expiry_secs")?;
+        positive(self.ping_interval_secs, "ping_interval_secs")?;
+        positive(self.message_idle_secs, "message_idle_secs")?;
+        positive(self.channel_capacity as u64, "channel_capacity")?;
+        if self.channel_capacity > MAX_CHANNEL_CAPACITY {
+            return Err(ConfigError::TooLarge(
+                "channel_capacity",
+                MAX_CHANNEL_CAPACITY,
+            ));
+        }
+        positive(
+            self.store.max_message_chars as u64,
+            "store.max_message_chars",
+        )?;
+        positive(self.store.max_messages as u64, "store.max_messages")?;
+        rate(
+            &self.rate_limits.message_session,
+            "rate_limits.message_session",
+        )?;
+        rate(&self.rate_limits.message_ip, "rate_limits.message_ip")?;
+        rate(&self.rate_limits.key_session, "rate_limits.key_session")?;
+        rate(&self.rate_limits.key_ip, "rate_limits.key_ip")?;
+        Ok(())
+    }
+
+    pub fn session_expiry(&self) -> Duration {
+        Duration::from_secs(self.session_expiry_secs)
+    }
+
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs)
+    }
+
+    pub fn message_idle(&self) -> Duration {
+        Duration::from_secs(self.message_idle_secs)
+    }
+
+    pub fn drain_deadline(&self) -> Duration {
+        Duration::from_secs(self.drain_deadline_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    /// Parse flags without reading the environment, so `CAVALIER_*` variables set where the tests
+    /// run can't change what they see
+    fn parse(flags: &[&str]) -> Args {
+        let matches = Args::command()
+            .mut_args(|arg| arg.env(None))
+            .try_get_matches_from(std::iter::once("cavalier-backend").chain(flags.iter().copied()))
+            .unwrap();
+        Args::from_arg_matches(&matches).unwrap()
+    }
+
+    #[test]
+    fn file_overrides_defaults() {
+        let config = Config::from_toml(
+            r#"
+            bind = "0.0.0.0:8080"
+            ping_interval_secs = 30
+
+            [store]
+            max_messages = 50
+
+            [rate_limits.key_ip]
+            per_sec = 300.0
+            burst = 2000.0
+            "#,
+        )
+        .unwrap();
+        let defaults = Config::default();
+        assert_eq!(config.bind, SocketAddr::from(([0, 0, 0, 0], 8080)));
+        assert_eq!(config.ping_interval_secs, 30);
+        assert_eq!(config.store.max_messages, 50);
+        assert_eq!(
+            config.store.max_message_chars,
+            defaults.store.max_message_chars
+        );
+        assert_eq!(config.rate_limits.key_ip.burst, 2000.0);
+        assert_eq!(
+            config.rate_limits.key_session,
+            defaults.rate_limits.key_session
+        );
+        assert_eq!(config.welcome_text, defaults.welcome_text);
+
+        assert!(Config::from_toml("ping_interval = 30").is_err());
+    }
+
+    #[test]
+    fn printed_config_reads_back() {
+        let config = Config {
+            sqlite_path: Some(PathBuf::from("/data/cavalier.db")),
+            metrics_bind: Some(SocketAddr::from(([0, 0, 0, 0], 9090))),
+            ..Config::default()
+        };
+        assert_eq!(Config::from_toml(&config.to_toml()).unwrap(), config);
+        let config = Config::default();
+        assert_eq!(Config::from_toml(&config.to_toml()).unwrap(), config);
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let dir = std::env::temp_dir().join(format!("cavalier-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cavalier.toml");
+        std::fs::write(&path, "message_idle_secs = 60\nchannel_capacity = 100\n").unwrap();
+
+        let args = parse(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--channel-capacity",
+            "500",
+            "--key-ip-burst",
+            "5000",
+            "--legacy-sockets",
+            "false",
+        ]);
+        let config = Config::load(&args).unwrap();
+        assert_eq!(config.message_idle_secs, 60);
+        assert_eq!(config.channel_capacity, 500);
+        assert_eq!(config.rate_limits.key_ip.burst, 5000.0);
+        assert!(!config.legacy_sockets);
+        assert_eq!(
+            config.rate_limits.key_ip.per_sec,
+            RateLimitConfig::default().key_ip.per_sec
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_settings_that_stop_the_server() {
+        let load = |flags: &[&str]| Config::load(&parse(flags));
+        assert!(load(&[]).is_ok());
+        assert!(load(&["--ping-interval-secs", "0"]).is_err());
+        assert!(load(&["--channel-capacity", "0"]).is_err());
+        assert!(load(&["--channel-capacity", &usize::MAX.to_string()]).is_err());
+        assert!(load(&["--max-messages", "0"]).is_err());
+        assert!(load(&["--message-session-burst", "0.5"]).is_err());
+        assert!(load(&["--key-ip-rate", "0"]).is_err());
+    }
+}
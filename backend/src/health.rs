@@ -0,0 +1,162 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Health probes and build info
+//!
+//! `/api/healthz` answers as long as the process can serve requests, for liveness probes.
+//! `/api/readyz` checks each component the chat needs and answers 503 if any of them is failing,
+//! for readiness probes, or once the server starts shutting down so no new clients are sent to it.
+//! `/api/version` tells which build is running.
+
+use crate::AppState;
+use crate::shutdown;
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tokio::time::{Duration, timeout};
+
+/// How long a readiness check waits for a lock before calling it stuck
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The git commit the server was built from, set by the build script
+const GIT_COMMIT: &str = env!("CAVALIER_GIT_COMMIT");
+
+/// Whether a component works, and why not if it doesn't
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Check {
+    Ok,
+    Failing { error: String },
+}
+
+/// Every component's check. The server is ready when all of them are `Ok`.
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    pub components: BTreeMap<&'static str, Check>,
+}
+
+/// Which build is running
+#[derive(Serialize, Debug)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub commit: &'static str,
+}
+
+/// Liveness: the process is up and serving requests
+pub async fn healthz_handler() -> impl IntoResponse {
+    (StatusCode::OK, Json(Check::Ok))
+}
+
+/// Readiness: the chat's state and store work, and the server isn't shutting down
+pub async fn readyz_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let readiness = readiness(&state).await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        tracing::warn!(?readiness, "not ready");
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+/// Build info: the crate version and git commit
+pub async fn version_handler() -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(BuildInfo {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            commit: GIT_COMMIT,
+        }),
+    )
+}
+
+/// Check every component
+pub async fn readiness(state: &AppState) -> Readiness {
+    let components = BTreeMap::from([
+        ("state", check_state(state).await),
+        ("store", check_store(state)),
+        ("shutdown", check_shutdown(state)),
+    ]);
+    Readiness {
+        ready: components.values().all(|check| *check == Check::Ok),
+        components,
+    }
+}
+
+/// The room map and every room's session maps can be locked. A lock held forever would hang every
+/// request that needs it.
+async fn check_state(state: &AppState) -> Check {
+    let locks = async {
+        let rooms = state.rooms.read().await;
+        for room in rooms.values() {
+            drop(room.session_to_message.read().await);
+            room.presence().await;
+        }
+    };
+    match timeout(LOCK_TIMEOUT, locks).await {
+        Ok(()) => Check::Ok,
+        Err(_) => Check::Failing {
+            error: format!("a lock was held for over {LOCK_TIMEOUT:?}"),
+        },
+    }
+}
+
+/// The server isn't shutting down
+fn check_shutdown(state: &AppState) -> Check {
+    if shutdown::shutting_down(&state.shutdown) {
+        Check::Failing {
+            error: String::from("the server is shutting down"),
+        }
+    } else {
+        Check::Ok
+    }
+}
+
+/// The message store can be read
+fn check_store(state: &AppState) -> Check {
+    match state.store.rooms() {
+        Ok(_) => Check::Ok,
+        Err(e) => Check::Failing {
+            error: e.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn ready_until_a_component_fails() {
+        let state = crate::test_state(Config::default());
+        let ready = readiness(&state).await;
+        assert!(ready.ready);
+        assert_eq!(ready.components.len(), 3);
+
+        let held = state.rooms.write().await;
+        let ready = readiness(&state).await;
+        assert!(!ready.ready);
+        assert!(matches!(ready.components["state"], Check::Failing { .. }));
+        assert_eq!(ready.components["store"], Check::Ok);
+        drop(held);
+    }
+}
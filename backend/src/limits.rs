@@ -0,0 +1,422 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Rate limits
+//!
+//! Making messages and typing keystrokes are each limited per session and per IP address with
+//! token buckets: a bucket holds up to `burst` tokens, refills at `per_sec` tokens a second, and
+//! every message or edit takes a token. Creating a room takes a message token. A session or
+//! address with an empty bucket is told to slow down: `/msg/new` and `/rooms` answer 429, and the
+//! key socket is closed with a policy violation.
+//!
+//! The per IP limits are looser than the per session ones, since many users can share an address.
+//! Behind reverse proxies every connection comes from the last proxy, so the client's address is
+//! read from `X-Forwarded-For` instead when `proxy_hops` says how many proxies there are. Only the
+//! entries the proxies appended are trusted; anything further left came from the client.
+//!
+//! Every limit can be configured; see `config`.
+
+use crate::AppState;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{HeaderMap, request::Parts},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+use tower_sessions::session::Id as SessionId;
+
+/// Past this many buckets, full ones are dropped; a full bucket is the same as no bucket. After
+/// that, they are only dropped again once the map has doubled, so a flood of keys doesn't make
+/// every check scan the map.
+const PRUNE_AT: usize = 10_000;
+
+/// How fast something may happen: `burst` at once, then `per_sec` a second
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+/// The rate of every limit
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub message_session: Rate,
+    pub message_ip: Rate,
+    pub key_session: Rate,
+    pub key_ip: Rate,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            message_session: Rate {
+                per_sec: 1.0,
+                burst: 10.0,
+            },
+            message_ip: Rate {
+                per_sec: 5.0,
+                burst: 50.0,
+            },
+            key_session: Rate {
+                per_sec: 30.0,
+                burst: 200.0,
+            },
+            key_ip: Rate {
+                per_sec: 150.0,
+                burst: 1_000.0,
+            },
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_sec).min(rate.burst);
+        self.updated = now;
+    }
+
+    fn has(&self, tokens: f64) -> bool {
+        self.tokens >= tokens
+    }
+}
+
+/// A token bucket for every key, all refilling at the same rate
+pub struct Limiter<K> {
+    rate: Rate,
+    buckets: Mutex<Buckets<K>>,
+}
+
+/// The buckets, and how many there can be before the next prune
+struct Buckets<K> {
+    map: HashMap<K, TokenBucket>,
+    prune_at: usize,
+}
+
+impl<K: Hash + Eq> Limiter<K> {
+    pub fn new(rate: Rate) -> Self {
+        Limiter {
+            rate,
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                prune_at: PRUNE_AT,
+            }),
+        }
+    }
+
+    /// Take `tokens` from `key`'s bucket if it has that many, returning whether it did. Only the
+    /// tests use one limiter alone; the server checks two at once with `allow`.
+    #[cfg(test)]
+    fn check_at(&self, key: K, tokens: f64, now: Instant) -> bool {
+        let mut buckets = self.lock();
+        let bucket = buckets.refilled(key, self.rate, now);
+        if !bucket.has(tokens) {
+            return false;
+        }
+        bucket.tokens -= tokens;
+        true
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Buckets<K>> {
+        // a poisoned map is still a map of buckets
+        self.buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<K: Hash + Eq> Buckets<K> {
+    /// `key`'s bucket, refilled up to `now`. Full buckets are dropped first if there are too many.
+    fn refilled(&mut self, key: K, rate: Rate, now: Instant) -> &mut TokenBucket {
+        if self.map.len() >= self.prune_at {
+            self.map.retain(|_, bucket| {
+                bucket.refill(rate, now);
+                bucket.tokens < rate.burst
+            });
+            self.prune_at = PRUNE_AT.max(self.map.len() * 2);
+        }
+        let bucket = self.map.entry(key).or_insert(TokenBucket {
+            tokens: rate.burst,
+            updated: now,
+        });
+        bucket.refill(rate, now);
+        bucket
+    }
+}
+
+/// Every rate limit, shared by all rooms
+pub struct RateLimits {
+    messages_by_session: Limiter<SessionId>,
+    messages_by_ip: Limiter<IpAddr>,
+    keys_by_session: Limiter<SessionId>,
+    keys_by_ip: Limiter<IpAddr>,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimits {
+            messages_by_session: Limiter::new(config.message_session),
+            messages_by_ip: Limiter::new(config.message_ip),
+            keys_by_session: Limiter::new(config.key_session),
+            keys_by_ip: Limiter::new(config.key_ip),
+        }
+    }
+
+    /// Whether the session and address may make another message
+    pub fn allow_message(&self, session_id: SessionId, ip: IpAddr) -> bool {
+        allow(
+            &self.messages_by_session,
+            &self.messages_by_ip,
+            session_id,
+            ip,
+            1,
+        )
+    }
+
+    /// Whether the session and address may make `edits` more edits
+    pub fn allow_edits(&self, session_id: SessionId, ip: IpAddr, edits: usize) -> bool {
+        allow(
+            &self.keys_by_session,
+            &self.keys_by_ip,
+            session_id,
+            ip,
+            edits,
+        )
+    }
+}
+
+/// Extractor for the address of the client a request came from, as rate limited
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        Ok(ClientIp(client_ip(
+            peer.ip(),
+            &parts.headers,
+            state.config.proxy_hops,
+        )))
+    }
+}
+
+/// The client's address: `peer` if there are no proxies, and otherwise the entry the outermost
+/// proxy appended to `X-Forwarded-For`. Falls back to `peer` if the header doesn't have it.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, proxy_hops: usize) -> IpAddr {
+    if proxy_hops == 0 {
+        return peer;
+    }
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    forwarded
+        .len()
+        .checked_sub(proxy_hops)
+        .and_then(|index| forwarded[index].parse().ok())
+        .unwrap_or(peer)
+}
+
+/// Take from the session's bucket and the address's. Both have to allow it, and neither is taken
+/// from otherwise, so a session over its own limit doesn't use up the address's, and a busy address
+/// doesn't use up the session's.
+fn allow(
+    by_session: &Limiter<SessionId>,
+    by_ip: &Limiter<IpAddr>,
+    session_id: SessionId,
+    ip: IpAddr,
+    tokens: usize,
+) -> bool {
+    let tokens = tokens as f64;
+    let now = Instant::now();
+    // always locked session first, so two checks can't wait on each other
+    let mut session_buckets = by_session.lock();
+    let mut ip_buckets = by_ip.lock();
+    let session = session_buckets.refilled(session_id, by_session.rate, now);
+    let address = ip_buckets.refilled(ip, by_ip.rate, now);
+    if !session.has(tokens) || !address.has(tokens) {
+        return false;
+    }
+    session.tokens -= tokens;
+    address.tokens -= tokens;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const RATE: Rate = Rate {
+        per_sec: 2.0,
+        burst: 5.0,
+    };
+
+    #[test]
+    fn flood_is_cut_to_burst_then_rate() {
+        let limiter = Limiter::new(RATE);
+        let start = Instant::now();
+        let allowed = (0..1_000)
+            .filter(|_| limiter.check_at("flood", 1.0, start))
+            .count();
+        assert_eq!(allowed, 5);
+
+        // after a second the bucket has refilled by two
+        let later = start + Duration::from_secs(1);
+        let allowed = (0..1_000)
+            .filter(|_| limiter.check_at("flood", 1.0, later))
+            .count();
+        assert_eq!(allowed, 2);
+
+        // and it never holds more than a burst
+        let much_later = start + Duration::from_secs(3_600);
+        let allowed = (0..1_000)
+            .filter(|_| limiter.check_at("flood", 1.0, much_later))
+            .count();
+        assert_eq!(allowed, 5);
+
+        // other keys have their own buckets
+        assert!(limiter.check_at("polite", 1.0, start));
+    }
+
+    #[test]
+    fn batches_take_a_token_each() {
+        let limiter = Limiter::new(RATE);
+        let now = Instant::now();
+        assert!(limiter.check_at((), 4.0, now));
+        assert!(!limiter.check_at((), 2.0, now));
+        assert!(limiter.check_at((), 1.0, now));
+    }
+
+    #[test]
+    fn full_buckets_are_pruned() {
+        let limiter = Limiter::new(RATE);
+        let start = Instant::now();
+        for key in 0..PRUNE_AT {
+            limiter.check_at(key, 1.0, start);
+        }
+        limiter.check_at(PRUNE_AT, 1.0, start + Duration::from_secs(60));
+        assert_eq!(limiter.buckets.lock().unwrap().map.len(), 1);
+    }
+
+    #[test]
+    fn busy_buckets_are_not_rescanned() {
+        let limiter = Limiter::new(RATE);
+        let now = Instant::now();
+        for key in 0..=PRUNE_AT {
+            limiter.check_at(key, 1.0, now);
+        }
+        // nothing could be dropped, so the next prune waits for the map to double
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.map.len(), PRUNE_AT + 1);
+        assert_eq!(buckets.prune_at, PRUNE_AT * 2);
+    }
+
+    #[test]
+    fn client_ip_comes_from_trusted_proxies() {
+        let peer = IpAddr::from([10, 0, 0, 2]);
+        let mut headers = HeaderMap::new();
+        assert_eq!(client_ip(peer, &headers, 1), peer);
+        // the client can claim any address, but the ingress appends the one it saw
+        headers.insert("x-forwarded-for", "192.0.2.1, 203.0.113.7".parse().unwrap());
+        assert_eq!(client_ip(peer, &headers, 0), peer);
+        assert_eq!(client_ip(peer, &headers, 1), IpAddr::from([203, 0, 113, 7]));
+        assert_eq!(client_ip(peer, &headers, 2), IpAddr::from([192, 0, 2, 1]));
+        assert_eq!(client_ip(peer, &headers, 3), peer);
+        headers.insert("x-forwarded-for", "unknown".parse().unwrap());
+        assert_eq!(client_ip(peer, &headers, 1), peer);
+    }
+
+    #[test]
+    fn sessions_share_their_address_limit() {
+        let limits = RateLimits::new(&RateLimitConfig {
+            message_session: Rate {
+                per_sec: 0.1,
+                burst: 2.0,
+            },
+            message_ip: Rate {
+                per_sec: 0.1,
+                burst: 5.0,
+            },
+            key_session: RATE,
+            key_ip: RATE,
+        });
+        let ip = IpAddr::from([203, 0, 113, 7]);
+        let allowed = (0..100)
+            .filter(|_| limits.allow_message(SessionId::default(), ip))
+            .count();
+        assert_eq!(allowed, 5);
+
+        // one session flooding is stopped before it uses up the address
+        let other_ip = IpAddr::from([203, 0, 113, 8]);
+        let session = SessionId::default();
+        let allowed = (0..100)
+            .filter(|_| limits.allow_message(session, other_ip))
+            .count();
+        assert_eq!(allowed, 2);
+        assert!(limits.allow_message(SessionId::default(), other_ip));
+        assert!(limits.allow_edits(session, other_ip, 5));
+        assert!(!limits.allow_edits(session, other_ip, 1));
+    }
+    #[test]
+    fn busy_address_leaves_sessions_their_tokens() {
+        let limits = RateLimits::new(&RateLimitConfig {
+            message_session: Rate {
+                per_sec: 0.001,
+                burst: 3.0,
+            },
+            message_ip: Rate {
+                per_sec: 0.001,
+                burst: 1.0,
+            },
+            key_session: RATE,
+            key_ip: RATE,
+        });
+        let crowded = IpAddr::from([203, 0, 113, 7]);
+        assert!(limits.allow_message(SessionId::default(), crowded));
+
+        // a neighbour used up the address, so this session is turned down without paying for it
+        let session = SessionId::default();
+        for _ in 0..10 {
+            assert!(!limits.allow_message(session, crowded));
+        }
+        let tokens = limits.messages_by_session.lock().map[&session].tokens;
+        assert_eq!(tokens, 3.0);
+    }
+}
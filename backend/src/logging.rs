@@ -0,0 +1,97 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Logging with `tracing`
+//!
+//! `RUST_LOG` picks what is logged (`info` when it is unset), and `log_format` picks between
+//! plain text and one JSON object per line for log pipelines.
+//!
+//! Every HTTP request runs in a `request` span and every websocket in a `ws` span. Both have a
+//! `session_id` field once the session is known, and a `message_id` field once there is a message.
+//! The `session_id` is a fingerprint of the session id, not the id itself: the id is the session
+//! cookie, and anyone who can read the logs could use it to type as someone else.
+
+use axum::{body::Body, http::Request};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use tower_sessions::{Session, session::Id as SessionId};
+use tracing::{Span, field::Empty};
+use tracing_subscriber::{EnvFilter, filter::LevelFilter};
+
+/// How log lines are written
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// A JSON object per line, with the fields of the spans it happened in
+    Json,
+}
+
+/// Start writing logs to stdout
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().with_span_list(true).init(),
+    }
+}
+
+/// The span a request is handled in, for `TraceLayer::make_span_with`
+pub fn request_span(request: &Request<Body>) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        session_id = Empty,
+        message_id = Empty,
+    )
+}
+
+/// What a session is logged as: stable for the life of the session, but useless as a cookie
+pub fn session_tag(session_id: &SessionId) -> String {
+    let mut hasher = DefaultHasher::new();
+    session_id.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Record the session on the current span, if it has an id yet
+pub fn record_session(session: &Session) {
+    if let Some(session_id) = session.id() {
+        Span::current().record("session_id", session_tag(&session_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_tag_hides_the_id() {
+        let session_id = SessionId::default();
+        let tag = session_tag(&session_id);
+        assert_eq!(tag, session_tag(&session_id));
+        assert_ne!(tag, session_tag(&SessionId::default()));
+        assert_ne!(tag, session_id.to_string());
+    }
+}
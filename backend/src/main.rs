@@ -18,14 +18,33 @@
 
 //! Axum backend for cavalier
 //!
-//! This backend provides three endpoints:
-//! 1. `/api/ws/events/`: A websocket for sending `Event`s (server -> client)
-//! 2. `/api/ws/key`: A websocket for sending keystrokes as binary arrays (server <-> client)
-//! 3. `/apt/msg/*`: JSON APIs for getting message data (server -> client)
+//! This backend provides these endpoints:
+//! 1. `/api/ws`: A websocket carrying `Event`s and keystrokes (server -> client) and edits
+//!    (client -> server) as tagged binary frames, in order, acking each batch of edits.
+//!    `?echo=false` leaves out the socket's own keystrokes. The older `/api/ws/events` (`Event`
+//!    jsons) and `/api/ws/key` (binary keystrokes) sockets are served while `legacy_sockets` is on.
+//! 2. `/apt/msg/*`: JSON APIs for getting message data (server -> client). History is paged with
+//!    `?before=<id>` and `?since=<id>` cursors, and `?raw=true` adds each message's keystroke log.
+//! 3. `/api/rooms`: JSON API for listing and creating chat rooms
+//! 4. `/api/presence`: JSON API for who is in the room. `UserJoined`, `UserLeft` and `Online`
+//!    events keep it up to date.
+//! 5. `/api/session/*`: starting a new session, and getting and naming its author
+//! 6. `/api/metrics`: Prometheus metrics, unless `metrics_bind` moves them to `/metrics` on their
+//!    own address; see `metrics`
+//! 7. `/api/healthz`, `/api/readyz` and `/api/version`: probes and build info; see `health`
 //!
-// TODO: instead of using expect() / panicing during websocket threads, end the loop and send
-// ws::Message::Close to terminate the connection gracefully. Also program behavior for when
-// ws::Message::Close is received from the socket to exit gracefully.
+//! Endpoints 1, 2 and 4 belong to a room. Under `/api/` they use the default room, and under
+//! `/api/rooms/{room}/` they use the named room.
+//!
+//! Every keystroke is stored with its offset from the start of its message, so
+//! `/api/msg/{id}/replay` can return the timed keystroke stream for playback.
+//!
+//! Websockets close with a close code and reason instead of panicking; see `ws`.
+//!
+//! Making messages and typing are rate limited per session and per IP address; see `limits`.
+//!
+//! Settings come from flags, environment variables and a config file; see `config`. Logs go
+//! through `tracing`; see `logging`.
 
 // TODO: refactor application
 // Ideas: since there is a global state, all routes accessing that state can go in their own
@@ -41,67 +60,39 @@
 
 use axum::{
     Json, Router,
-    extract::{
-        State, WebSocketUpgrade,
-        ws::{self, WebSocket},
-    },
+    extract::{Path, Query, State},
     http::StatusCode,
-    response::{IntoResponse, Response},
+    middleware,
+    response::IntoResponse,
     routing::{any, get},
 };
-use bytes::{BufMut, Bytes, BytesMut};
-use serde::{Deserialize, Serialize};
+use cavalier_protocol::{Event, Message, RawMessage};
+use clap::Parser;
+use config::{Args, Config};
+use limits::{ClientIp, RateLimits};
+use metrics::Metrics;
+use rooms::{CurrentRoom, OpenMessage, Room, Update};
+use serde::Deserialize;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use store::{MemoryMessageStore, MessageStore, SqliteMessageStore, StoreError};
 // use serde_json::Result;
-use futures_util::{
-    sink::SinkExt,
-    stream::{SplitSink, SplitStream, StreamExt},
-};
 use std::collections::HashMap;
-use tokio::{
-    sync::{
-        Mutex, RwLock,
-        broadcast::{self, Sender},
-    },
-    time::{Duration, interval},
-};
+use tokio::sync::{RwLock, watch};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
 use tower_sessions::{Expiry, MemoryStore, Session, SessionManagerLayer, session::Id as SessionId};
-
-/***********************\
-* Global Structs, Enums *
-\***********************/
-// TODO: refactor: move these into shared crate
-
-/// A completed Message.
-///
-/// The text contains all keystrokes, including backspace.
-/// Timings will be added
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Message {
-    id: u32,
-    text: String,
-}
-
-/// A keystroke
-///
-/// Associates key char with message_id, timing, any other info.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Keystroke {
-    message_id: u32,
-    key: char,
-    /* time: std::time::Duration, */  //this will get added in when DB functionality is added
-}
-
-/// An event
-///
-/// Communicates from the server to the client that a new message has been created, a message is
-/// over, there is a new user, etc any live updates the client could want
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "event", content = "data")]
-enum Event {
-    MessageNew(Message),
-    MessageEnd,
-}
+use tracing::{Level, Span};
+
+mod authors;
+mod config;
+mod health;
+mod limits;
+mod logging;
+mod metrics;
+mod rooms;
+mod shutdown;
+mod store;
+mod ws;
 
 /**********************\
 * Main, Routing, State *
@@ -109,114 +100,283 @@ enum Event {
 
 #[derive(Clone)]
 struct AppState {
-    key_tx: Sender<Keystroke>,
-    event_tx: Sender<Event>,
-    messages: Arc<RwLock<Vec<Message>>>,
-    session_to_message: Arc<RwLock<HashMap<SessionId, u32>>>,
-    /* db connection */
+    rooms: Arc<RwLock<HashMap<String, Arc<Room>>>>,
+    store: Arc<dyn MessageStore>,
+    limits: Arc<RateLimits>,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    /// Raised when the server starts shutting down; see `shutdown`
+    shutdown: watch::Receiver<bool>,
 }
 
 #[tokio::main]
 async fn main() {
-    #[cfg(not(debug_assertions))]
-    const BASE_URL: &'static str = "0.0.0.0:80";
-    #[cfg(debug_assertions)]
-    const BASE_URL: &'static str = "127.0.0.1:3000";
+    let args = Args::parse();
+    let config = Config::load(&args).unwrap_or_else(|e| {
+        eprintln!("Error loading config: {e}");
+        std::process::exit(2);
+    });
+    if args.print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+    logging::init(config.log_format);
 
-    let listener = tokio::net::TcpListener::bind(BASE_URL)
+    let listener = tokio::net::TcpListener::bind(config.bind)
         .await
-        .expect(format!("Could not bind to {BASE_URL}").as_str());
-    println!("Listening on {:?}", listener.local_addr().unwrap());
+        .unwrap_or_else(|e| panic!("Could not bind to {}: {e}", config.bind));
+    tracing::info!(addr = %listener.local_addr().unwrap(), "listening");
+    let metrics_listener = match config.metrics_bind {
+        Some(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .unwrap_or_else(|e| panic!("Could not bind metrics to {addr}: {e}"));
+            tracing::info!(addr = %listener.local_addr().unwrap(), "serving metrics");
+            Some(listener)
+        }
+        None => None,
+    };
 
-    let (key_tx, _) = broadcast::channel(10_000); // Keystroke tx
-    let (event_tx, _) = broadcast::channel(10_000); // event tx
+    // Messages are kept in RAM unless the deployment opts in to SQLite
+    let store: Arc<dyn MessageStore> = match &config.sqlite_path {
+        Some(path) => {
+            tracing::info!(path = %path.display(), "storing messages in SQLite");
+            Arc::new(
+                SqliteMessageStore::open(path)
+                    .expect("Could not open SQLite database")
+                    .with_limits(config.store),
+            )
+        }
+        None => Arc::new(MemoryMessageStore::default().with_limits(config.store)),
+    };
+    let rooms =
+        rooms::load_rooms(&store, &config).expect("Could not load rooms from the message store");
 
-    let mut msgvec = Vec::<Message>::from([Message {
-        id: 0,
-        text: String::from(
-            "Hello! Welcome to Cavalier Extralive Chat. As you type your message, it will reflect to your friends in real time. No prose, just rash and cavalier messages! All messages are anonymous and stored in RAM, thus they are securely deleted when the server restarts. This project is provided to you under the GNU AGPLv3. To see the source code of this app, visit https://github.com/samfield1/cavalier/ 🫠 你们随便玩儿",
-        ),
-    }]);
-    msgvec.reserve(10);
-    let messages = Arc::new(RwLock::new(msgvec));
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let state = AppState {
+        rooms: Arc::new(RwLock::new(rooms)),
+        store,
+        limits: Arc::new(RateLimits::new(&config.rate_limits)),
+        config: Arc::new(config),
+        metrics: Arc::new(Metrics::default()),
+        shutdown: shutdown_rx,
+    };
 
-    let session_to_message_map = HashMap::<SessionId, u32>::new();
-    let session_to_message = Arc::new(RwLock::new(session_to_message_map));
+    tokio::spawn(rooms::finish_idle_messages_task(
+        state.rooms.clone(),
+        state.config.message_idle(),
+    ));
+
+    serve(
+        listener,
+        metrics_listener,
+        state,
+        shutdown_tx,
+        shutdown::signal(),
+    )
+    .await;
+}
 
-    let state = AppState {
-        key_tx,
-        event_tx,
-        messages,
-        session_to_message,
+/// Serve clients on `listener`, and metrics on `metrics_listener` if there is one, until `signal`
+/// resolves. Then drain, until every socket has closed or the drain deadline passes.
+///
+/// `state` has to be the only thing holding `shutdown`'s receiver, since the drain waits for
+/// every receiver to be dropped.
+async fn serve(
+    listener: tokio::net::TcpListener,
+    metrics_listener: Option<tokio::net::TcpListener>,
+    state: AppState,
+    shutdown_tx: watch::Sender<bool>,
+    signal: impl Future<Output = ()>,
+) {
+    let rooms = state.rooms.clone();
+    let drain_deadline = state.config.drain_deadline();
+
+    if let Some(listener) = metrics_listener {
+        let metrics_router = Router::new()
+            .route("/metrics", get(metrics::metrics_handler)) // prometheus metrics
+            .with_state(state.clone());
+        let mut stopped = state.shutdown.clone();
+        tokio::spawn(async move {
+            let served = axum::serve(listener, metrics_router)
+                .with_graceful_shutdown(async move {
+                    stopped.wait_for(|stopped| *stopped).await.ok();
+                })
+                .await;
+            if let Err(e) = served {
+                tracing::error!(error = %e, "metrics server stopped");
+            }
+        });
+    }
+    let mut stopped = state.shutdown.clone();
+    // takes the last of the state, so nothing outside the servers keeps the shutdown flag
+    let router = router(state);
+    // the peer's address is needed for the per IP rate limits
+    let mut server = tokio::spawn(async move {
+        axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move {
+            stopped.wait_for(|stopped| *stopped).await.ok();
+        })
+        .await
+    });
+
+    tokio::select! {
+        result = &mut server => {
+            tracing::error!(?result, "server stopped unexpectedly");
+            std::process::exit(1);
+        }
+        () = signal => {}
+    }
+    tracing::info!(deadline = ?drain_deadline, "shutting down");
+    let drained = async {
+        shutdown::drain(&rooms, &shutdown_tx).await;
+        if let Ok(Err(e)) = server.await {
+            tracing::error!(error = %e, "server error while shutting down");
+        }
+        // every socket holds the shutdown flag until it closes
+        shutdown_tx.closed().await;
     };
+    if tokio::time::timeout(drain_deadline, drained).await.is_err() {
+        tracing::warn!("drain deadline passed, closing the remaining connections");
+    }
+    tracing::info!("shut down");
+}
 
+/// Every client-facing route, with the session, tracing and metrics layers
+fn router(state: AppState) -> Router {
+    let session_expiry =
+        time::Duration::try_from(state.config.session_expiry()).unwrap_or(time::Duration::MAX);
     let session_store = MemoryStore::default();
     let session_layer = SessionManagerLayer::new(session_store)
         .with_secure(true)
-        .with_expiry(Expiry::OnInactivity(time::Duration::minutes(10)))
+        .with_expiry(Expiry::OnInactivity(session_expiry))
         .with_path("/api");
 
-    let router = Router::new()
-        .route("/api/ws/events", any(events_handler)) // client <-> server event communication
-        .route("/api/ws/key", any(key_handler)) // client <-> server keystrokes communication
-        .route("/api/msg/new", any(msg_new_handler)) // json API: writing new message
-        .route("/api/msg/get", get(msg_get_handler)) // json API: get existing messages
+    // routes scoped to a room
+    let mut room_router = Router::new()
+        .route("/ws", any(ws::socket_handler)) // client <-> server events and keystrokes
+        .route("/msg/new", any(msg_new_handler)) // json API: writing new message
+        .route("/msg/get", get(msg_get_handler)) // json API: get existing messages
+        .route("/msg/{id}/replay", get(msg_replay_handler)) // json API: timed keystrokes
+        .route("/presence", get(rooms::presence_handler)); // json API: who is in the room
+    if state.config.legacy_sockets {
+        room_router = room_router
+            .route("/ws/events", any(ws::events_handler)) // server -> client events
+            .route("/ws/key", any(ws::key_handler)); // client <-> server keystrokes
+    }
+
+    let mut router = Router::new()
+        .nest("/api/rooms/{room}", room_router.clone()) // a named room
+        .nest("/api", room_router) // the default room
+        .route(
+            "/api/rooms",
+            get(rooms::rooms_list_handler).post(rooms::rooms_new_handler),
+        ) // json API: list and create rooms
         .route("/api/session/new", any(session_new_handler)) // associate user with new session
-        .route("/api/test", any(test_handler)) // test if axum is running
-        .layer(session_layer)
-        .with_state(state);
-    axum::serve(listener, router).await.unwrap()
-}
+        .route(
+            "/api/session/author",
+            get(authors::author_get_handler).post(authors::author_nickname_handler),
+        ); // json API: who the session types as, and its nickname
+    if state.config.metrics_bind.is_none() {
+        router = router.route("/api/metrics", get(metrics::metrics_handler)); // prometheus metrics
+    }
 
-async fn test_handler(_: State<AppState>) -> axum::response::Html<&'static str> {
-    axum::response::Html("<h1 style=\"text-align: center;\">GET test</h1>")
+    router
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_http,
+        )) // times requests by route, so it only wraps requests that matched one
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(logging::request_span)
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        ) // inside the session layer, so handlers record their session on the request span
+        .layer(session_layer)
+        // probes come too often to log, and have no session
+        .route("/api/healthz", get(health::healthz_handler)) // liveness probe
+        .route("/api/readyz", get(health::readyz_handler)) // readiness probe
+        .route("/api/version", get(health::version_handler)) // json API: build info
+        .with_state(state)
 }
 
 /*******************\
 * Message JSON APIs *
 \*******************/
 
-#[axum::debug_handler]
-async fn msg_new_handler(State(state): State<AppState>, session: Session) -> impl IntoResponse {
-    session.insert("preserve", true).await.unwrap(); // ensures session
-    let msgs_ref = state.messages.clone();
-    let event_tx = state.event_tx.clone();
-    let msg_id: u32;
-    let new_msg: Message;
-    // add to global state vec
-    // inside scope to drop guard at end
-    if let Ok(mut msgs) = msgs_ref.try_write() {
-        msg_id = u32::try_from(msgs.len()).expect("Message id u32 overflow!");
-        new_msg = Message {
-            id: msg_id,
-            text: String::new(),
-        };
-        (*msgs).push(new_msg.clone());
-    } else {
+#[axum::debug_handler(state = AppState)]
+/// Make a new message for the session to type into, finishing the one it was typing before.
+///
+/// The client calls this when Send is pressed, so this is also how messages get sent.
+async fn msg_new_handler(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    CurrentRoom(room): CurrentRoom,
+    session: Session,
+) -> impl IntoResponse {
+    if shutdown::shutting_down(&state.shutdown) {
         return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json("Could not access messages, try again."),
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json("The server is shutting down, try again soon."),
         )
             .into_response();
+    }
+    // a client without a session gets one here, so it is limited as a session from its first
+    // message
+    let session_id = match ensure_session(&session).await {
+        Ok(session_id) => session_id,
+        Err(e) => {
+            tracing::error!(error = %e, "could not save session");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("Could not access session, try again."),
+            )
+                .into_response();
+        }
     };
-
-    // TODO: the session check must go above the new message allocation
-    // add to global session RwLock
-    match session.id() {
-        Some(session_id) => {
-            let session_to_message_ref = state.session_to_message.clone();
-            let mut session_to_message = (*session_to_message_ref).write().await;
-            session_to_message.insert(session_id, msg_id);
+    logging::record_session(&session);
+    if !state.limits.allow_message(session_id, ip) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json("Making messages too fast, slow down."),
+        )
+            .into_response();
+    }
+    room.finish_session_message(&session_id).await;
+    let author = match authors::session_author(&session).await {
+        Ok(author) => author,
+        Err(e) => {
+            tracing::error!(error = %e, "could not get session author");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("Could not access session, try again."),
+            )
+                .into_response();
         }
-        None => {
-            eprintln!("Session: {:?}", session);
+    };
+    // add to the message store
+    let new_msg: Message = match room.store.new_message(&room.name, Some(&author)) {
+        Ok(new_msg) => new_msg,
+        Err(e) => {
+            tracing::error!(error = %e, "could not create message");
             return (
-                StatusCode::BAD_REQUEST,
-                Json("Session must be set to make a new message"),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("Could not access messages, try again."),
             )
                 .into_response();
         }
-    }
+    };
+    let msg_id = new_msg.id;
+    state.metrics.messages_created.inc();
+    Span::current().record("message_id", msg_id);
+
+    // add to global session RwLock
+    room.session_to_message
+        .write()
+        .await
+        .insert(session_id, OpenMessage::new(msg_id));
     // This is not used, because I need the message_id to update while the websocket threads are
     // running. Therefore, the session is just assosciated with an Arc<RwLock<HashMap<>>> inside
     // the global app state struct
@@ -227,268 +387,290 @@ async fn msg_new_handler(State(state): State<AppState>, session: Session) -> imp
 
     // transmit new message event
     let new_msg_event = Event::MessageNew(new_msg.clone());
-    if let Err(e) = event_tx.send(new_msg_event) {
-        eprintln!("Error broadcasting new message: {e}")
+    if let Err(e) = room.update_tx.send(Update::Event(new_msg_event)) {
+        tracing::debug!(error = %e, "could not broadcast new message")
     }
+    // the room grows a message at a time, so this is when it may have outgrown its limits
+    room.evict_messages();
     (StatusCode::OK, Json(new_msg)).into_response()
 }
 
-async fn msg_get_handler(State(state): State<AppState>, session: Session) -> impl IntoResponse {
+/// Messages per page of history when the client doesn't ask for a size
+const HISTORY_PAGE_DEFAULT: usize = 50;
+
+/// The most messages a single page of history can hold
+const HISTORY_PAGE_MAX: usize = 200;
+
+/// Query parameters of `/msg/get`. `before` and `since` are message id cursors, and can't be used
+/// together.
+#[derive(Deserialize)]
+struct HistoryQuery {
+    before: Option<u32>,
+    since: Option<u32>,
+    limit: Option<usize>,
+    #[serde(default)]
+    raw: bool,
+}
+
+/// Get a page of messages, ordered by id.
+///
+/// - No cursor: the newest page
+/// - `?before=<id>`: the page of messages just older than `id`, for scrolling back
+/// - `?since=<id>`: the page of messages just newer than `id`, for catching up
+///
+/// A page shorter than `limit` means there is nothing further in that direction.
+///
+/// Messages come with their rendered text. `?raw=true` sends `RawMessage`s instead, which also
+/// have the keystroke log the text was rendered from.
+async fn msg_get_handler(
+    CurrentRoom(room): CurrentRoom,
+    Query(query): Query<HistoryQuery>,
+    session: Session,
+) -> impl IntoResponse {
     session.insert("preserve", true).await.unwrap();
-    let msgs = state.messages.clone();
-    let Ok(msgs) = msgs.try_read() else {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json("Could not access messages, try again."),
-        )
-            .into_response();
+    let limit = query
+        .limit
+        .unwrap_or(HISTORY_PAGE_DEFAULT)
+        .clamp(1, HISTORY_PAGE_MAX);
+    let page = match (query.before, query.since) {
+        (before, None) => room.store.messages_before(&room.name, before, limit),
+        (None, Some(since)) => room.store.messages_since(&room.name, since, limit),
+        (Some(_), Some(_)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json("Use either before or since, not both"),
+            )
+                .into_response();
+        }
     };
-
-    (StatusCode::OK, Json(&*msgs)).into_response()
+    let page = match page {
+        Ok(msgs) if query.raw => with_keystrokes(&room, msgs).map(|raw| Json(raw).into_response()),
+        page => page.map(|msgs| Json(msgs).into_response()),
+    };
+    match page {
+        Ok(page) => (StatusCode::OK, page).into_response(),
+        Err(e) => {
+            tracing::error!(error = %e, "could not get messages");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("Could not access messages, try again."),
+            )
+                .into_response()
+        }
+    }
 }
 
-/************\
-* Event Code *
-\************/
-
-async fn events_handler(ws: WebSocketUpgrade, state: State<AppState>) -> Response {
-    ws.on_upgrade(|ws| ws_events_handler(ws, state))
+/// Pair every message with its keystroke log
+fn with_keystrokes(room: &Room, msgs: Vec<Message>) -> Result<Vec<RawMessage>, StoreError> {
+    msgs.into_iter()
+        .map(|message| {
+            let keystrokes = room
+                .store
+                .keystrokes(&room.name, message.id)?
+                .unwrap_or_default();
+            Ok(RawMessage {
+                message,
+                keystrokes,
+            })
+        })
+        .collect()
 }
 
-/// Send updates to the client live as `Event` jsons
-async fn ws_events_handler(ws: WebSocket, State(state): State<AppState>) {
-    let mut event_rx = state.event_tx.subscribe();
-
-    let (sender, mut receiver) = ws.split();
-
-    // Always read from the socket to keep it alive
-    tokio::spawn(async move {
-        // TODO: think about updating both websocket handlers to deal with close and pingpong. However, recognize the
-        // limited utility, because the main thread's send loop has no way to access this
-        // information without an Arc<Mutex<>>
-        while let Some(Ok(msg)) = receiver.next().await {
-            match msg {
-                ws::Message::Close(_) => {
-                    dbg!("/api/ws/events/: received Close");
-                    break;
-                }
-                ws::Message::Pong(_) => {
-                    dbg!("/api/ws/events/: received Pong");
-                }
-                _msg => {}
-            }
-        }
-    });
-    let shared_sender = Arc::new(Mutex::new(sender));
-
-    let ping_sender = shared_sender.clone();
-    tokio::spawn(async move {
-        let mut interval = interval(Duration::from_secs(10));
-        loop {
-            interval.tick().await;
-            if let Err(e) = ping_sender
-                .lock()
-                .await
-                .send(ws::Message::Ping(Bytes::from_static(&[8u8])))
-                .await
-            {
-                eprintln!("Event ping error: {e}");
-                break;
-            }
-        }
-    });
+/// Path parameters of the `/msg/{id}/` routes. Any `{room}` parameter is left to `CurrentRoom`.
+#[derive(Deserialize)]
+struct MessagePath {
+    id: u32,
+}
 
-    // Send events
-    let event_sender = shared_sender.clone();
-    while let Ok(event) = event_rx.recv().await {
-        let json = serde_json::to_string(&event).unwrap();
-        let ws_msg = ws::Message::Text(json.into());
-        if let Err(e) = event_sender.lock().await.send(ws_msg).await {
-            eprintln!("Event send error: {e}");
-            break;
+/// Get the timed keystrokes of a message so the client can play it back
+async fn msg_replay_handler(
+    CurrentRoom(room): CurrentRoom,
+    Path(MessagePath { id: message_id }): Path<MessagePath>,
+) -> impl IntoResponse {
+    match room.store.keystrokes(&room.name, message_id) {
+        Ok(Some(keystrokes)) => (StatusCode::OK, Json(keystrokes)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, Json("Message does not exist")).into_response(),
+        Err(e) => {
+            tracing::error!(error = %e, message_id, "could not get keystrokes");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("Could not access messages, try again."),
+            )
+                .into_response()
         }
     }
 }
 
-/**********************************\
-* Client <-> Server Keystroke Code *
-\**********************************/
-
-async fn key_handler(ws: WebSocketUpgrade, state: State<AppState>, session: Session) -> Response {
-    session.insert("preserve", true).await.unwrap(); // ensures session
-    if session.id().is_none() {
-        return (StatusCode::BAD_REQUEST, "Session id is not set!").into_response();
+/// The session's id, saving the session first if it is new. New sessions only get an id once
+/// they are saved.
+async fn ensure_session(session: &Session) -> Result<SessionId, tower_sessions::session::Error> {
+    session.insert("preserve", true).await?;
+    if let Some(session_id) = session.id() {
+        return Ok(session_id);
     }
-    ws.on_upgrade(|ws| ws_key_handler(ws, state, session))
+    session.save().await?;
+    Ok(session.id().expect("saving a session gives it an id"))
 }
 
-/// Split websocket into send/recv and propogate to separate client -> server and server -> client
-/// functions
-async fn ws_key_handler(ws: WebSocket, state: State<AppState>, session: Session) {
-    let (ws_tx, ws_rx) = ws.split();
-    let mut s2c_task = tokio::spawn(ws_s2c_task(ws_tx, state.clone(), session.clone()));
-    let mut c2s_task = tokio::spawn(ws_c2s_task(ws_rx, state, session));
-    tokio::select! {
-        s2c_result = (&mut s2c_task) => {
-            match s2c_result {
-                Ok(_) => {},
-                Err(e) => eprintln!("Error sending keystrokes: {e}")
-            }
-            c2s_task.abort();
-        },
-        c2s_result = (&mut c2s_task) => {
-            match c2s_result {
-                Ok(_) => {},
-                Err(e) => eprintln!("Error receiving keystrokes {e}")
-            }
-            s2c_task.abort();
+async fn session_new_handler(State(state): State<AppState>, session: Session) -> impl IntoResponse {
+    // the old session may have been typing in any room
+    if let Some(session_id) = session.id() {
+        for room in state.rooms.read().await.values() {
+            room.finish_session_message(&session_id).await;
         }
     }
+    session.delete().await.ok();
 
-    /***********************\
-    * Server -> Client Code *
-    \***********************/
-
-    /// receive keystrokes from the key_rx
-    /// send keystrokes to all clients but the originator
-    /// Also ping tje client every 10 seconds
-    async fn ws_s2c_task(
-        ws_tx: SplitSink<WebSocket, ws::Message>,
-        State(state): State<AppState>,
-        _session: Session,
-    ) {
-        let mut rx = state.key_tx.subscribe();
-        let shared_ws_tx = Arc::new(Mutex::new(ws_tx));
-
-        let ping_ws_tx = shared_ws_tx.clone();
-        tokio::spawn(async move {
-            let mut interval = interval(Duration::from_secs(10));
-            loop {
-                interval.tick().await;
-                if let Err(e) = ping_ws_tx
-                    .lock()
-                    .await
-                    .send(ws::Message::Ping(Bytes::from_static(&[8u8])))
-                    .await
-                {
-                    eprintln!("Event ping error: {e}");
-                    break;
-                }
-            }
-        });
+    session.insert("preserve", true).await.ok();
+    StatusCode::OK
+}
 
-        let key_ws_tx = shared_ws_tx.clone();
-        loop {
-            match rx.recv().await {
-                Ok(keystroke) => {
-                    // don't broadcast if the keystroke came from this session
-                    // TODO: reevaluate if this is useful. It is turned off now for two reasons:
-                    // 1. easier to debug
-                    // 2. The client only echoing the character when the server responds gives the
-                    //    user hangup when lagging instead of false feedback
-                    // let self_msg: bool;
-                    // {
-                    //     let session_id = session
-                    //         .id()
-                    //         .expect("ws key_tx task started without session id");
-                    //     let session_to_message_ref = state.session_to_message.clone();
-                    //     let session_to_message = (*session_to_message_ref).read().await;
-                    //     self_msg = match session_to_message.get(&session_id) {
-                    //         Some(message_id) => *message_id == keystroke.message_id,
-                    //         None => false,
-                    //     }
-                    //     // then only broadcast if self_msg == false
-                    // }
-
-                    // build buffer.
-                    // first 4 bytes = little endian char/key
-                    // last 4 bytes = little endian message id
-                    let mut buffer = BytesMut::with_capacity(8);
-                    buffer.put_u32_le(keystroke.key as u32);
-                    buffer.put_u32_le(keystroke.message_id);
-                    let msg_bytes = buffer.freeze();
-                    let ws_msg = ws::Message::Binary(msg_bytes);
-                    if let Err(e) = key_ws_tx.lock().await.send(ws_msg).await {
-                        eprintln!("Error sending ws_tx: {e}");
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error receiving key_rx: {e}");
-                }
-            }
+/// The state of a server with the default room in RAM, for tests
+#[cfg(test)]
+fn test_state(config: Config) -> AppState {
+    let store: Arc<dyn MessageStore> = Arc::new(MemoryMessageStore::default());
+    let rooms = rooms::load_rooms(&store, &config).unwrap();
+    AppState {
+        rooms: Arc::new(RwLock::new(rooms)),
+        store,
+        limits: Arc::new(RateLimits::new(&config.rate_limits)),
+        config: Arc::new(config),
+        metrics: Arc::new(Metrics::default()),
+        shutdown: watch::channel(false).1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        extract::connect_info::MockConnectInfo,
+        http::{Request, Response, header},
+    };
+    use limits::Rate;
+    use tower::ServiceExt;
+
+    const SLOW: f64 = 0.001;
+
+    /// Make a message, as the session in `cookie` if there is one
+    async fn new_msg(app: &Router, cookie: Option<&str>) -> Response<Body> {
+        let mut request = Request::post("/api/msg/new");
+        if let Some(cookie) = cookie {
+            request = request.header(header::COOKIE, cookie);
         }
+        app.clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
     }
 
-    /***********************\
-    * Client -> Server Code *
-    \***********************/
-
-    /// receive keystrokes from the client
-    /// send keystrokes down the key_tx
-    async fn ws_c2s_task(
-        mut ws_rx: SplitStream<WebSocket>,
-        State(state): State<AppState>,
-        session: Session,
-    ) {
-        let key_tx = state.key_tx.clone();
-        while let Some(Ok(msg)) = ws_rx.next().await {
-            match msg {
-                ws::Message::Binary(body) if body.len() != 4 => {
-                    eprintln!("Invalid character length: {:?}", body);
-                }
-                ws::Message::Binary(body) => {
-                    // interpret message
-                    // 4 bytes long, little endian keystroke/char
-                    let key_bytes: [u8; 4] = body[0..4].try_into().unwrap();
-                    let key_int: u32 = u32::from_le_bytes(key_bytes);
-                    if let Some(key) = char::from_u32(key_int) {
-                        let session_id = session.id().expect(
-                            "Session was not set before client broadcasted keystrokes to server",
-                        );
-                        let message_id: u32;
-                        {
-                            let session_to_message_ref = state.session_to_message.clone();
-                            let session_to_message = (*session_to_message_ref).read().await;
-                            message_id = *session_to_message.get(&session_id).expect("Client broadcasted keystrokes to server without having a message created.");
-                        }
-
-                        let keystroke = Keystroke { message_id, key };
-                        if let Err(e) = key_tx.send(keystroke) {
-                            eprintln!("Keystroke send error: {e}");
-                        }
-                        {
-                            let messages_ref = state.messages.clone();
-                            let mut messages = (*messages_ref).write().await;
-                            match messages.get_mut(message_id as usize) {
-                                Some(message) => message.text.push(key),
-                                None => eprintln!(
-                                    "Message id {message_id} not found in global messages vec"
-                                ),
-                            }
-                        }
-                    } else {
-                        eprintln!("Bad key received: {:?}", body);
-                    }
-                }
-                ws::Message::Pong(_) => {
-                    dbg!("/api/ws/keys/: Pong received");
-                }
-                _ => {
-                    eprintln!("Invalid ws key message received: {:?}", msg);
-                }
-            }
+    #[tokio::test]
+    async fn message_flood_is_rate_limited() {
+        let mut config = Config::default();
+        config.rate_limits.message_session = Rate {
+            per_sec: SLOW,
+            burst: 3.0,
+        };
+        config.rate_limits.message_ip = Rate {
+            per_sec: SLOW,
+            burst: 5.0,
+        };
+        let app = router(test_state(config))
+            .layer(MockConnectInfo(SocketAddr::from(([203, 0, 113, 7], 4000))));
+
+        // a client without a cookie is given a session, and limited as it from the start
+        let first = new_msg(&app, None).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        let set_cookie = first.headers()[header::SET_COOKIE].to_str().unwrap();
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+        let mut statuses = Vec::new();
+        for _ in 0..5 {
+            statuses.push(new_msg(&app, Some(&cookie)).await.status());
         }
+        assert_eq!(statuses[..2], [StatusCode::OK; 2]);
+        assert_eq!(statuses[2..], [StatusCode::TOO_MANY_REQUESTS; 3]);
+
+        // dropping the cookie gets a new session, but not a new address
+        assert_eq!(new_msg(&app, None).await.status(), StatusCode::OK);
+        assert_eq!(new_msg(&app, None).await.status(), StatusCode::OK);
+        assert_eq!(
+            new_msg(&app, None).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
     }
-}
+    #[tokio::test]
+    async fn room_flood_is_rate_limited() {
+        let mut config = Config::default();
+        config.rate_limits.message_ip = Rate {
+            per_sec: SLOW,
+            burst: 2.0,
+        };
+        let app = router(test_state(config))
+            .layer(MockConnectInfo(SocketAddr::from(([203, 0, 113, 7], 4000))));
+        let new_room = |name: &str| {
+            let request = Request::post("/api/rooms")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(format!(r#"{{"name":"{name}"}}"#)))
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
 
-async fn session_new_handler(state: State<AppState>, session: Session) -> impl IntoResponse {
-    state
-        .session_to_message
-        .write()
-        .await
-        .remove(&session.id().unwrap());
-    session.delete().await.ok();
+        assert_eq!(new_room("x").await, StatusCode::CREATED);
+        assert_eq!(new_room("y").await, StatusCode::CREATED);
+        assert_eq!(new_room("z").await, StatusCode::TOO_MANY_REQUESTS);
+        // joining a room that already exists is free
+        assert_eq!(new_room("x").await, StatusCode::OK);
+    }
 
-    session.insert("preserve", true).await.ok();
-    StatusCode::OK
+    #[tokio::test]
+    async fn metrics_move_off_the_api_with_their_own_address() {
+        let scrape = |config: Config| async move {
+            router(test_state(config))
+                .oneshot(Request::get("/api/metrics").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status()
+        };
+        assert_eq!(scrape(Config::default()).await, StatusCode::OK);
+        let config = Config {
+            metrics_bind: Some(SocketAddr::from(([127, 0, 0, 1], 9090))),
+            ..Config::default()
+        };
+        assert_eq!(scrape(config).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn shutdown_without_sockets_skips_the_deadline() {
+        async fn bind() -> tokio::net::TcpListener {
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap()
+        }
+        for serve_metrics in [false, true] {
+            let config = Config {
+                drain_deadline_secs: 60,
+                ..Config::default()
+            };
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+            let state = AppState {
+                shutdown: shutdown_rx,
+                ..test_state(config)
+            };
+            let metrics_listener = match serve_metrics {
+                true => Some(bind().await),
+                false => None,
+            };
+
+            let served = serve(
+                bind().await,
+                metrics_listener,
+                state,
+                shutdown_tx,
+                std::future::ready(()),
+            );
+            tokio::time::timeout(std::time::Duration::from_secs(5), served)
+                .await
+                .expect("shutdown waited for sockets that were never opened");
+        }
+    }
 }
@@ -0,0 +1,248 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Prometheus metrics, served at `/api/metrics`, or at `/metrics` on `metrics_bind` when it is set
+//!
+//! They tell how busy every room and route is, so deployments with a public ingress should set
+//! `metrics_bind` to serve them on their own address instead of next to the chat.
+//!
+//! Counters only go up; rates like keystrokes per second come from the scraper, e.g.
+//! `rate(cavalier_keystrokes_relayed_total[1m])`. Gauges that can be read off the rooms, like the
+//! number of sessions with an open message, are read when the metrics are scraped.
+
+use crate::AppState;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::time::Instant;
+
+/// Every metric the server keeps, and the registry they are scraped from
+pub struct Metrics {
+    registry: Registry,
+    /// Open websockets, by endpoint
+    sockets: IntGaugeVec,
+    /// Messages made through `/msg/new`
+    pub messages_created: IntCounter,
+    /// Keystrokes stored and broadcast to the room
+    pub keystrokes_relayed: IntCounter,
+    /// Times a socket fell behind its room's broadcast channel, by endpoint
+    broadcast_lags: IntCounterVec,
+    /// Updates skipped by sockets that fell behind, by endpoint
+    broadcast_missed: IntCounterVec,
+    /// Sessions with an open message, across every room
+    typing_sessions: IntGauge,
+    /// HTTP response times, by method, route and status
+    http_duration: HistogramVec,
+}
+
+/// The websocket endpoints, as they are labelled
+const SOCKET_ENDPOINTS: [&str; 3] = ["/ws", "/ws/events", "/ws/key"];
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let sockets = IntGaugeVec::new(
+            Opts::new("cavalier_sockets_connected", "Open websockets"),
+            &["endpoint"],
+        )
+        .unwrap();
+        let messages_created =
+            IntCounter::new("cavalier_messages_created_total", "Messages made").unwrap();
+        let keystrokes_relayed = IntCounter::new(
+            "cavalier_keystrokes_relayed_total",
+            "Keystrokes stored and broadcast to their room",
+        )
+        .unwrap();
+        let broadcast_lags = IntCounterVec::new(
+            Opts::new(
+                "cavalier_broadcast_lag_events_total",
+                "Times a socket fell behind its room's broadcast channel",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
+        let broadcast_missed = IntCounterVec::new(
+            Opts::new(
+                "cavalier_broadcast_missed_total",
+                "Updates skipped by sockets that fell behind their room's broadcast channel",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
+        let typing_sessions = IntGauge::new(
+            "cavalier_typing_sessions",
+            "Sessions with an open message, across every room",
+        )
+        .unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "cavalier_http_request_duration_seconds",
+                "HTTP response times",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+
+        // start every series at zero, so a scrape shows it before anything happens
+        for endpoint in SOCKET_ENDPOINTS {
+            sockets.with_label_values(&[endpoint]);
+            broadcast_lags.with_label_values(&[endpoint]);
+            broadcast_missed.with_label_values(&[endpoint]);
+        }
+
+        let registry = Registry::new();
+        registry.register(Box::new(sockets.clone())).unwrap();
+        registry
+            .register(Box::new(messages_created.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(keystrokes_relayed.clone()))
+            .unwrap();
+        registry.register(Box::new(broadcast_lags.clone())).unwrap();
+        registry
+            .register(Box::new(broadcast_missed.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(typing_sessions.clone()))
+            .unwrap();
+        registry.register(Box::new(http_duration.clone())).unwrap();
+
+        Metrics {
+            registry,
+            sockets,
+            messages_created,
+            keystrokes_relayed,
+            broadcast_lags,
+            broadcast_missed,
+            typing_sessions,
+            http_duration,
+        }
+    }
+}
+
+impl Metrics {
+    /// Count a socket as connected until the returned guard is dropped
+    pub fn socket_connected(&self, endpoint: &str) -> SocketGuard {
+        let gauge = self.sockets.with_label_values(&[endpoint]);
+        gauge.inc();
+        SocketGuard(gauge)
+    }
+
+    /// Count a socket falling `missed` updates behind its room's broadcast channel
+    pub fn broadcast_lagged(&self, endpoint: &str, missed: u64) {
+        self.broadcast_lags.with_label_values(&[endpoint]).inc();
+        self.broadcast_missed
+            .with_label_values(&[endpoint])
+            .inc_by(missed);
+    }
+
+    /// The metrics in the Prometheus text format
+    fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+/// Keeps a socket counted in `cavalier_sockets_connected` while it is alive
+pub struct SocketGuard(IntGauge);
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Serve the metrics for Prometheus to scrape
+pub async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let mut typing = 0;
+    for room in state.rooms.read().await.values() {
+        typing += room.session_to_message.read().await.len();
+    }
+    state
+        .metrics
+        .typing_sessions
+        .set(i64::try_from(typing).unwrap_or(i64::MAX));
+
+    match state.metrics.encode() {
+        Ok(body) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+            body,
+        )
+            .into_response(),
+        Err(e) => {
+            tracing::error!(error = %e, "could not encode metrics");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not encode metrics",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Middleware timing every request to a route. Must be a route layer, so the route is known.
+pub async fn track_http(
+    State(state): State<AppState>,
+    route: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let route = route.as_ref().map_or("unknown", |route| route.as_str());
+    state
+        .metrics
+        .http_duration
+        .with_label_values(&[method.as_str(), route, response.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sockets_are_counted_while_open() {
+        let metrics = Metrics::default();
+        let gauge = metrics.sockets.with_label_values(&["/ws/key"]);
+        let first = metrics.socket_connected("/ws/key");
+        let second = metrics.socket_connected("/ws/key");
+        assert_eq!(gauge.get(), 2);
+        drop(first);
+        assert_eq!(gauge.get(), 1);
+        drop(second);
+        assert_eq!(gauge.get(), 0);
+
+        metrics.broadcast_lagged("/ws", 40);
+        metrics.messages_created.inc();
+        let text = metrics.encode().unwrap();
+        assert!(text.contains(r#"cavalier_broadcast_missed_total{endpoint="/ws"} 40"#));
+        assert!(text.contains(r#"cavalier_sockets_connected{endpoint="/ws/events"} 0"#));
+        assert!(text.contains("cavalier_messages_created_total 1"));
+    }
+}
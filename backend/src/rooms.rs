@@ -0,0 +1,694 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Chat rooms
+//!
+//! Every room has its own broadcast channel, messages and session -> message map, so rooms never
+//! see each other's traffic. Events and keystrokes share the channel, so every socket sees them in
+//! the order they happened. The messages themselves live in the `MessageStore`, keyed by room
+//! name. The room routes are mounted twice: under `/api/rooms/{room}/`, and directly under `/api/`
+//! for the default room.
+//!
+//! A session types into one open message per room at a time. The message is finished when the
+//! session makes a new one (pressing Send), has no sockets open past a short grace period, or stops
+//! typing for too long; finishing it broadcasts `Event::MessageEnd`. The grace period lets a
+//! client that lost its connection reconnect and keep typing the same message.
+//!
+//! A client that falls too far behind the broadcast channel gets an `Event::Resync` snapshot of the
+//! messages it may have missed updates to, instead of being left with a wrong view.
+//!
+//! A session is present in a room while it has an `/api/ws` or events socket open to it. Its first
+//! socket broadcasts `Event::UserJoined` and its last broadcasts `Event::UserLeft`, each followed
+//! by `Event::Online`, and `/api/presence` lists everyone present.
+
+use crate::AppState;
+use crate::config::Config;
+use crate::limits::ClientIp;
+use crate::logging;
+use crate::store::{MessageStore, StoreError};
+use axum::{
+    Json,
+    extract::{FromRequestParts, Path, State},
+    http::{StatusCode, request::Parts},
+    response::{IntoResponse, Response},
+};
+use cavalier_protocol::{
+    Author, DEFAULT_ROOM, Event, Keystroke, Message, Presence, RoomInfo, valid_room_name,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::{
+    sync::{
+        RwLock,
+        broadcast::{self, Sender},
+    },
+    time::{Duration, Instant},
+};
+use tower_sessions::{Session, session::Id as SessionId};
+
+pub const WELCOME_TEXT: &str = "Hello! Welcome to Cavalier Extralive Chat. As you type your message, it will reflect to your friends in real time. No prose, just rash and cavalier messages! All messages are anonymous and stored in RAM, thus they are securely deleted when the server restarts. This project is provided to you under the GNU AGPLv3. To see the source code of this app, visit https://github.com/samfield1/cavalier/ 🫠 你们随便玩儿";
+
+/// Rooms are cheap, but not free. Past this point, a new room takes the place of an empty one, and
+/// can't be created if none are empty.
+const MAX_ROOMS: usize = 1_000;
+
+/// How long a disconnected session has to reconnect before its message is finished
+const RECONNECT_GRACE: Duration = Duration::from_secs(15);
+
+/// How many of the newest messages go in a resync snapshot, along with every open one.
+///
+/// Finished messages can only have changed while a client was behind if they were open during
+/// that time, and those are almost always among the newest.
+const RESYNC_RECENT: usize = 50;
+
+/// A chat room and everything happening in it
+pub struct Room {
+    pub name: String,
+    pub update_tx: Sender<Update>,
+    pub store: Arc<dyn MessageStore>,
+    pub session_to_message: RwLock<HashMap<SessionId, OpenMessage>>,
+    present: RwLock<HashMap<SessionId, PresentSession>>,
+    /// How many `/api/ws` or key sockets each session can type on
+    typing_sockets: RwLock<HashMap<SessionId, usize>>,
+}
+
+/// Something that happened in a room, as broadcast to every socket open to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Update {
+    Event(Event),
+    /// A keystroke, and the socket it was typed on
+    Keystroke(Keystroke, SocketId),
+}
+
+/// Tells apart the websockets open to the server, so a socket can know its own keystrokes
+pub type SocketId = u64;
+
+/// A session with sockets open to a room, and who it is
+struct PresentSession {
+    author: Author,
+    sockets: usize,
+}
+
+/// The message a session is typing, when it last typed into it, and when its author's socket
+/// closed if it is not connected
+pub struct OpenMessage {
+    pub id: u32,
+    pub last_active: Instant,
+    pub disconnected: Option<Instant>,
+}
+
+impl OpenMessage {
+    pub fn new(id: u32) -> Self {
+        OpenMessage {
+            id,
+            last_active: Instant::now(),
+            disconnected: None,
+        }
+    }
+
+    /// Whether the message should be finished because its author went away
+    fn abandoned(&self, idle_timeout: Duration) -> bool {
+        self.last_active.elapsed() >= idle_timeout
+            || self
+                .disconnected
+                .is_some_and(|since| since.elapsed() >= RECONNECT_GRACE)
+    }
+}
+
+impl Room {
+    /// Open a room, creating it in the store with a welcome message if it is new
+    pub fn new(
+        name: String,
+        store: Arc<dyn MessageStore>,
+        config: &Config,
+    ) -> Result<Self, StoreError> {
+        store.create_room(&name, &config.welcome_text)?;
+
+        let (update_tx, _) = broadcast::channel(config.channel_capacity);
+
+        Ok(Room {
+            name,
+            update_tx,
+            store,
+            session_to_message: RwLock::new(HashMap::new()),
+            present: RwLock::new(HashMap::new()),
+            typing_sockets: RwLock::new(HashMap::new()),
+        })
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            name: self.name.clone(),
+        }
+    }
+
+    /// The messages a client that fell behind may have missed updates to: every open message, and
+    /// the newest few finished ones
+    pub async fn resync_snapshot(&self) -> Result<Vec<Message>, StoreError> {
+        let mut messages = self
+            .store
+            .messages_before(&self.name, None, RESYNC_RECENT)?;
+        let open: Vec<u32> = self
+            .session_to_message
+            .read()
+            .await
+            .values()
+            .map(|open| open.id)
+            .collect();
+        for id in open {
+            if messages.iter().any(|message| message.id == id) {
+                continue;
+            }
+            // the newest message below the next id is the open one, if it is still there
+            let older = self
+                .store
+                .messages_before(&self.name, Some(id.saturating_add(1)), 1)?;
+            messages.extend(older.into_iter().filter(|message| message.id == id));
+        }
+        messages.sort_by_key(|message| message.id);
+        Ok(messages)
+    }
+
+    /// Whether nobody is in the room and nobody has ever typed in it, so it can make way for
+    /// another
+    async fn is_empty(&self) -> Result<bool, StoreError> {
+        if !self.present.read().await.is_empty() || !self.session_to_message.read().await.is_empty()
+        {
+            return Ok(false);
+        }
+        let newest = self.store.messages_before(&self.name, None, 1)?;
+        Ok(newest.iter().all(|message| message.id == 0))
+    }
+
+    /// Everyone present, sorted by name
+    pub async fn presence(&self) -> Presence {
+        let mut users: Vec<Author> = self
+            .present
+            .read()
+            .await
+            .values()
+            .map(|present| present.author.clone())
+            .collect();
+        users.sort_by(|a, b| a.display_name().cmp(b.display_name()));
+        Presence { users }
+    }
+
+    /// A session opened an `/api/ws` or events socket to the room. Announced if it wasn't already here.
+    pub async fn join(&self, session_id: SessionId, author: Author) {
+        let mut present = self.present.write().await;
+        match present.get_mut(&session_id) {
+            Some(session) => session.sockets += 1,
+            None => {
+                present.insert(
+                    session_id,
+                    PresentSession {
+                        author: author.clone(),
+                        sockets: 1,
+                    },
+                );
+                self.announce(Event::UserJoined(author), present.len());
+            }
+        }
+    }
+
+    /// A session closed an `/api/ws` or events socket to the room. Announced if it was its last.
+    pub async fn leave(&self, session_id: &SessionId) {
+        let mut present = self.present.write().await;
+        let Some(session) = present.get_mut(session_id) else {
+            return;
+        };
+        if session.sockets > 1 {
+            session.sockets -= 1;
+            return;
+        }
+        if let Some(session) = present.remove(session_id) {
+            self.announce(Event::UserLeft(session.author), present.len());
+        }
+    }
+
+    /// Broadcast a presence change and the number of users it leaves in the room
+    fn announce(&self, event: Event, count: usize) {
+        let online = Event::Online {
+            count: u32::try_from(count).unwrap_or(u32::MAX),
+        };
+        for event in [event, online] {
+            if let Err(e) = self.update_tx.send(Update::Event(event)) {
+                tracing::debug!(room = %self.name, error = %e, "could not broadcast presence");
+            }
+        }
+    }
+
+    /// Finish a message in the store, and tell every client it is over
+    pub fn finish_message(&self, message_id: u32) {
+        match self.store.finish_message(&self.name, message_id) {
+            Ok(true) => {
+                if let Err(e) = self
+                    .update_tx
+                    .send(Update::Event(Event::MessageEnd { id: message_id }))
+                {
+                    tracing::debug!(room = %self.name, message_id, error = %e, "could not broadcast message end");
+                }
+            }
+            Ok(false) => {} // already finished
+            Err(e) => {
+                tracing::error!(room = %self.name, message_id, error = %e, "could not finish message")
+            }
+        }
+    }
+
+    /// Drop old messages until the room fits in the store's limits, and tell every client
+    pub fn evict_messages(&self) {
+        let evicted = match self.store.evict(&self.name) {
+            Ok(evicted) => evicted,
+            Err(e) => {
+                tracing::error!(room = %self.name, error = %e, "could not evict messages");
+                return;
+            }
+        };
+        for id in evicted {
+            if let Err(e) = self
+                .update_tx
+                .send(Update::Event(Event::MessageEvicted { id }))
+            {
+                tracing::debug!(room = %self.name, message_id = id, error = %e, "could not broadcast message eviction");
+            }
+        }
+    }
+
+    /// Finish the message a session is typing, if it has one
+    pub async fn finish_session_message(&self, session_id: &SessionId) {
+        let open = self.session_to_message.write().await.remove(session_id);
+        if let Some(open) = open {
+            self.finish_message(open.id);
+        }
+    }
+
+    /// One of the session's `/api/ws` or key sockets closed. If it was the last, its message is
+    /// finished unless it reconnects in time.
+    pub async fn disconnect_session(&self, session_id: &SessionId) {
+        let mut typing_sockets = self.typing_sockets.write().await;
+        let Some(sockets) = typing_sockets.get_mut(session_id) else {
+            return;
+        };
+        if *sockets > 1 {
+            *sockets -= 1;
+            return;
+        }
+        typing_sockets.remove(session_id);
+        if let Some(open) = self.session_to_message.write().await.get_mut(session_id) {
+            open.disconnected = Some(Instant::now());
+        }
+    }
+
+    /// The session's `/api/ws` or key socket (re)opened, so its message is no longer abandoned
+    pub async fn connect_session(&self, session_id: &SessionId) {
+        *self
+            .typing_sockets
+            .write()
+            .await
+            .entry(*session_id)
+            .or_default() += 1;
+        if let Some(open) = self.session_to_message.write().await.get_mut(session_id) {
+            open.disconnected = None;
+        }
+    }
+
+    /// Finish every message whose author has not typed for `idle_timeout`, or disconnected and
+    /// did not come back
+    pub async fn finish_idle_messages(&self, idle_timeout: Duration) {
+        let mut idle = Vec::new();
+        self.session_to_message.write().await.retain(|_, open| {
+            let abandoned = open.abandoned(idle_timeout);
+            if abandoned {
+                idle.push(open.id);
+            }
+            !abandoned
+        });
+        for message_id in idle {
+            self.finish_message(message_id);
+        }
+    }
+
+    /// Finish every open message, and tell every client the server is going away
+    pub async fn shut_down(&self) {
+        let open: Vec<u32> = self
+            .session_to_message
+            .write()
+            .await
+            .drain()
+            .map(|(_, open)| open.id)
+            .collect();
+        for message_id in open {
+            self.finish_message(message_id);
+        }
+        if let Err(e) = self
+            .update_tx
+            .send(Update::Event(Event::ServerShuttingDown))
+        {
+            tracing::debug!(room = %self.name, error = %e, "could not broadcast shutdown");
+        }
+    }
+}
+
+/// Periodically finish the messages of authors who walked away or disconnected mid-message
+pub async fn finish_idle_messages_task(
+    rooms: Arc<RwLock<HashMap<String, Arc<Room>>>>,
+    idle_timeout: Duration,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        // clone the rooms out so creating a room doesn't wait on the sweep
+        let rooms: Vec<Arc<Room>> = rooms.read().await.values().cloned().collect();
+        for room in rooms {
+            room.finish_idle_messages(idle_timeout).await;
+        }
+    }
+}
+
+/// Make the room map from the rooms already in the store, plus the default room
+pub fn load_rooms(
+    store: &Arc<dyn MessageStore>,
+    config: &Config,
+) -> Result<HashMap<String, Arc<Room>>, StoreError> {
+    let mut names = store.rooms()?;
+    names.push(String::from(DEFAULT_ROOM));
+    names
+        .into_iter()
+        .map(|name| {
+            let room = Room::new(name.clone(), store.clone(), config)?;
+            Ok((name, Arc::new(room)))
+        })
+        .collect()
+}
+
+/// Drop an empty room other than the default one, from the map and the store. Returns whether
+/// there was one.
+async fn drop_empty_room(
+    rooms: &mut HashMap<String, Arc<Room>>,
+    store: &dyn MessageStore,
+) -> Result<bool, StoreError> {
+    let mut empty = None;
+    for (name, room) in rooms.iter() {
+        if name != DEFAULT_ROOM && room.is_empty().await? {
+            empty = Some(name.clone());
+            break;
+        }
+    }
+    let Some(name) = empty else {
+        return Ok(false);
+    };
+    rooms.remove(&name);
+    store.delete_room(&name)?;
+    tracing::info!(room = %name, "dropped empty room to make space");
+    Ok(true)
+}
+
+/// Extractor for the room a request is scoped to.
+///
+/// This is the `{room}` path parameter when there is one, and the default room otherwise.
+pub struct CurrentRoom(pub Arc<Room>);
+
+impl FromRequestParts<AppState> for CurrentRoom {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let params = Option::<Path<HashMap<String, String>>>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let name = params
+            .as_ref()
+            .and_then(|Path(params)| params.get("room"))
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_ROOM);
+        match state.rooms.read().await.get(name) {
+            Some(room) => Ok(CurrentRoom(room.clone())),
+            None => Err((StatusCode::NOT_FOUND, Json("Room does not exist")).into_response()),
+        }
+    }
+}
+
+/*****************\
+* Room JSON APIs *
+\*****************/
+
+/// List every room, sorted by name
+pub async fn rooms_list_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let rooms = state.rooms.read().await;
+    let mut infos: Vec<RoomInfo> = rooms.values().map(|room| room.info()).collect();
+    infos.sort_by(|a, b| a.name.cmp(&b.name));
+    (StatusCode::OK, Json(infos))
+}
+
+/// List everyone in the room
+pub async fn presence_handler(CurrentRoom(room): CurrentRoom) -> impl IntoResponse {
+    (StatusCode::OK, Json(room.presence().await))
+}
+
+/// Create a room. Creating a room that already exists is not an error, it is simply joined.
+///
+/// Creating rooms takes from the same rate limits as making messages.
+pub async fn rooms_new_handler(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    session: Session,
+    Json(new_room): Json<RoomInfo>,
+) -> impl IntoResponse {
+    if !valid_room_name(&new_room.name) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json("Room names may only contain a-z, 0-9, - and _"),
+        )
+            .into_response();
+    }
+    let mut rooms = state.rooms.write().await;
+    if let Some(room) = rooms.get(&new_room.name) {
+        return (StatusCode::OK, Json(room.info())).into_response();
+    }
+    let session_id = match crate::ensure_session(&session).await {
+        Ok(session_id) => session_id,
+        Err(e) => {
+            tracing::error!(error = %e, "could not save session");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("Could not access session, try again."),
+            )
+                .into_response();
+        }
+    };
+    logging::record_session(&session);
+    if !state.limits.allow_message(session_id, ip) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json("Making rooms too fast, slow down."),
+        )
+            .into_response();
+    }
+    if rooms.len() >= MAX_ROOMS {
+        match drop_empty_room(&mut rooms, state.store.as_ref()).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Json("Too many rooms, try again later."),
+                )
+                    .into_response();
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "could not drop an empty room");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json("Could not create room, try again."),
+                )
+                    .into_response();
+            }
+        }
+    }
+    let room = match Room::new(new_room.name.clone(), state.store.clone(), &state.config) {
+        Ok(room) => Arc::new(room),
+        Err(e) => {
+            tracing::error!(room = %new_room.name, error = %e, "could not create room");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json("Could not create room, try again."),
+            )
+                .into_response();
+        }
+    };
+    let info = room.info();
+    rooms.insert(new_room.name, room);
+    (StatusCode::CREATED, Json(info)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryMessageStore;
+
+    fn test_room() -> Room {
+        Room::new(
+            String::from("test"),
+            Arc::new(MemoryMessageStore::default()),
+            &Config::default(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn resync_snapshot_has_open_and_recent_messages() {
+        let room = test_room();
+        let open = room.store.new_message(&room.name, None).unwrap();
+        room.session_to_message
+            .write()
+            .await
+            .insert(SessionId::default(), OpenMessage::new(open.id));
+        for _ in 0..RESYNC_RECENT {
+            let message = room.store.new_message(&room.name, None).unwrap();
+            room.store.finish_message(&room.name, message.id).unwrap();
+        }
+        let ids: Vec<u32> = room
+            .resync_snapshot()
+            .await
+            .unwrap()
+            .iter()
+            .map(|message| message.id)
+            .collect();
+        // the welcome message is too old to have changed, but the open message still might
+        assert_eq!(ids[0], open.id);
+        assert_eq!(ids.len(), RESYNC_RECENT + 1);
+        assert!(!ids.contains(&0));
+    }
+
+    #[tokio::test]
+    async fn presence_counts_sessions() {
+        let room = test_room();
+        let mut update_rx = room.update_tx.subscribe();
+        let author = |pseudonym: &str| Author {
+            pseudonym: String::from(pseudonym),
+            nickname: None,
+        };
+        let (a, b) = (SessionId::default(), SessionId::default());
+
+        room.join(a, author("Quiet Heron 3")).await;
+        room.join(a, author("Quiet Heron 3")).await; // a second tab
+        room.join(b, author("Brisk Otter 7")).await;
+        room.leave(&a).await;
+        let users: Vec<String> = room
+            .presence()
+            .await
+            .users
+            .into_iter()
+            .map(|user| user.pseudonym)
+            .collect();
+        assert_eq!(users, ["Brisk Otter 7", "Quiet Heron 3"]);
+        room.leave(&a).await;
+        room.leave(&a).await; // already gone
+        assert_eq!(room.presence().await.users.len(), 1);
+
+        let mut updates = Vec::new();
+        while let Ok(update) = update_rx.try_recv() {
+            updates.push(update);
+        }
+        assert_eq!(
+            updates,
+            [
+                Event::UserJoined(author("Quiet Heron 3")),
+                Event::Online { count: 1 },
+                Event::UserJoined(author("Brisk Otter 7")),
+                Event::Online { count: 2 },
+                Event::UserLeft(author("Quiet Heron 3")),
+                Event::Online { count: 1 },
+            ]
+            .map(Update::Event)
+        );
+    }
+
+    #[tokio::test]
+    async fn message_stays_open_while_another_tab_is_connected() {
+        let room = test_room();
+        let session_id = SessionId::default();
+        let message = room.store.new_message(&room.name, None).unwrap();
+        room.session_to_message
+            .write()
+            .await
+            .insert(session_id, OpenMessage::new(message.id));
+        let disconnected = |room: &Room| {
+            room.session_to_message
+                .try_read()
+                .unwrap()
+                .get(&session_id)
+                .unwrap()
+                .disconnected
+                .is_some()
+        };
+
+        room.connect_session(&session_id).await;
+        room.connect_session(&session_id).await; // a second tab
+        room.disconnect_session(&session_id).await;
+        assert!(!disconnected(&room));
+        room.disconnect_session(&session_id).await;
+        assert!(disconnected(&room));
+        room.connect_session(&session_id).await;
+        assert!(!disconnected(&room));
+    }
+
+    #[tokio::test]
+    async fn only_empty_rooms_make_way() {
+        let store: Arc<dyn MessageStore> = Arc::new(MemoryMessageStore::default());
+        let config = Config::default();
+        let mut rooms = load_rooms(&store, &config).unwrap();
+        for name in ["busy", "quiet"] {
+            let room = Room::new(String::from(name), store.clone(), &config).unwrap();
+            rooms.insert(String::from(name), Arc::new(room));
+        }
+        store.new_message("busy", None).unwrap();
+
+        assert!(drop_empty_room(&mut rooms, store.as_ref()).await.unwrap());
+        assert!(!rooms.contains_key("quiet"));
+        assert!(!store.rooms().unwrap().contains(&String::from("quiet")));
+        // the default room is empty too, but always kept
+        assert!(!drop_empty_room(&mut rooms, store.as_ref()).await.unwrap());
+        assert_eq!(rooms.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn shut_down_finishes_messages_then_says_goodbye() {
+        let room = test_room();
+        let mut update_rx = room.update_tx.subscribe();
+        let message = room.store.new_message(&room.name, None).unwrap();
+        room.session_to_message
+            .write()
+            .await
+            .insert(SessionId::default(), OpenMessage::new(message.id));
+
+        room.shut_down().await;
+        assert_eq!(
+            update_rx.try_recv().unwrap(),
+            Update::Event(Event::MessageEnd { id: message.id })
+        );
+        assert_eq!(
+            update_rx.try_recv().unwrap(),
+            Update::Event(Event::ServerShuttingDown)
+        );
+        assert!(room.session_to_message.read().await.is_empty());
+        assert!(room.store.messages(&room.name).unwrap()[1].finished);
+    }
+}
@@ -0,0 +1,70 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Graceful shutdown
+//!
+//! On SIGTERM or SIGINT the server drains instead of severing every socket:
+//! 1. The shutdown flag is raised. Legacy key sockets close with "going away", and new sockets
+//!    and messages are refused.
+//! 2. Every open message is finished, and every room is sent `Event::ServerShuttingDown`.
+//!    `/api/ws` and events sockets relay everything up to it, then close with "going away".
+//! 3. The server stops accepting connections and finishes the requests in flight.
+//!
+//! The process exits once every socket has closed, or when the drain deadline passes.
+
+use crate::rooms::Room;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{RwLock, watch};
+
+/// Wait for SIGTERM (what k8s sends) or SIGINT (Ctrl-C)
+pub async fn signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Could not listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Could not listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => tracing::info!("received SIGINT"),
+        () = terminate => tracing::info!("received SIGTERM"),
+    }
+}
+
+/// Raise the shutdown flag, then finish every room's messages and tell its clients
+pub async fn drain(rooms: &RwLock<HashMap<String, Arc<Room>>>, shutdown: &watch::Sender<bool>) {
+    shutdown.send_replace(true);
+    let rooms: Vec<Arc<Room>> = rooms.read().await.values().cloned().collect();
+    for room in rooms {
+        room.shut_down().await;
+    }
+}
+
+/// Whether the server is shutting down, for handlers that should refuse new work
+pub fn shutting_down(shutdown: &watch::Receiver<bool>) -> bool {
+    *shutdown.borrow()
+}
@@ -0,0 +1,458 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Message storage
+//!
+//! Messages and their keystroke logs are kept behind the `MessageStore` trait. By default they
+//! live in RAM (`MemoryMessageStore`) and are gone when the server restarts. Deployments that want
+//! history to survive restarts can opt in to `SqliteMessageStore` by setting
+//! `CAVALIER_SQLITE_PATH`.
+//!
+//! A message's text is stored rendered, with every edit already applied, so it is worked out once
+//! per edit instead of by every client. The keystroke log it was rendered from is only ever
+//! appended to.
+//!
+//! Every store is bounded by its `StoreLimits`: messages stop growing at a maximum length, and
+//! each room keeps a maximum number of messages and bytes of text, dropping its oldest finished
+//! messages to make room. Message ids keep counting up after messages are dropped, so an id is
+//! never reused.
+//!
+//! The stores are synchronous. Every call is a quick lookup or append, so handlers call them
+//! directly instead of going through `spawn_blocking`.
+
+use cavalier_protocol::{Author, Edit, Keystroke, Message};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+mod memory;
+mod sqlite;
+
+pub use memory::MemoryMessageStore;
+pub use sqlite::SqliteMessageStore;
+
+/// Why a store operation failed
+#[derive(Debug)]
+pub enum StoreError {
+    /// The room has used every message id
+    IdOverflow,
+    Sqlite(rusqlite::Error),
+    /// A lock was poisoned by a panicking thread
+    Poisoned,
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::IdOverflow => write!(f, "message id u32 overflow"),
+            StoreError::Sqlite(e) => write!(f, "sqlite error: {e}"),
+            StoreError::Poisoned => write!(f, "store lock poisoned"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Sqlite(e)
+    }
+}
+
+/// How big messages and rooms can get
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreLimits {
+    /// The most chars a message can hold. Typing past it is dropped.
+    pub max_message_chars: usize,
+    /// The most messages a room keeps. At least one, the newest, is always kept.
+    pub max_messages: usize,
+    /// The most bytes of message text a room keeps
+    pub max_room_bytes: usize,
+}
+
+impl Default for StoreLimits {
+    fn default() -> Self {
+        StoreLimits {
+            max_message_chars: 2_000,
+            max_messages: 10_000,
+            max_room_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Where the messages of every room are kept
+pub trait MessageStore: Send + Sync {
+    /// Names of every stored room
+    fn rooms(&self) -> Result<Vec<String>, StoreError>;
+
+    /// Make a room whose first message is `welcome`. Does nothing if the room already exists.
+    fn create_room(&self, room: &str, welcome: &str) -> Result<(), StoreError>;
+
+    /// Forget a room, along with its messages and their keystrokes
+    fn delete_room(&self, room: &str) -> Result<(), StoreError>;
+
+    /// Make a new, empty message with the next id in the room, typed by `author`
+    fn new_message(&self, room: &str, author: Option<&Author>) -> Result<Message, StoreError>;
+
+    /// Every message in the room, ordered by id. Rooms can be large, so only the tests load them
+    /// whole; the server pages with `messages_before` and `messages_since`.
+    #[cfg(test)]
+    fn messages(&self, room: &str) -> Result<Vec<Message>, StoreError>;
+
+    /// The newest `limit` messages with ids below `before`, or the newest `limit` messages in the
+    /// room if `before` is `None`. Ordered by id.
+    fn messages_before(
+        &self,
+        room: &str,
+        before: Option<u32>,
+        limit: usize,
+    ) -> Result<Vec<Message>, StoreError>;
+
+    /// The oldest `limit` messages with ids above `since`, ordered by id
+    fn messages_since(
+        &self,
+        room: &str,
+        since: u32,
+        limit: usize,
+    ) -> Result<Vec<Message>, StoreError>;
+
+    /// Apply an edit to a message's text, and log it stamped with the time since the message was
+    /// created.
+    ///
+    /// Inserts are cut short at the store's `max_message_chars`. Returns `None` if the message
+    /// does not exist, is finished, or is already full.
+    fn push_edit(
+        &self,
+        room: &str,
+        message_id: u32,
+        edit: Edit,
+    ) -> Result<Option<Keystroke>, StoreError>;
+
+    /// The timed keystrokes of a message, in the order they were typed.
+    ///
+    /// Returns `None` if the message does not exist.
+    fn keystrokes(&self, room: &str, message_id: u32)
+    -> Result<Option<Vec<Keystroke>>, StoreError>;
+
+    /// Mark a message as finished, so no more keys can be typed into it.
+    ///
+    /// Returns whether the message was open before this call.
+    fn finish_message(&self, room: &str, message_id: u32) -> Result<bool, StoreError>;
+
+    /// Drop the room's oldest finished messages, along with their keystrokes, until it fits in
+    /// the store's limits. Returns the ids of the dropped messages.
+    fn evict(&self, room: &str) -> Result<Vec<u32>, StoreError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(at: u32, text: &str) -> Edit {
+        Edit::Insert {
+            at,
+            text: String::from(text),
+        }
+    }
+
+    /// Run the same scenario against a store, so every implementation behaves the same
+    fn exercise(store: &dyn MessageStore) {
+        store.create_room("lobby", "welcome").unwrap();
+        store.create_room("lobby", "ignored").unwrap();
+        store.create_room("other", "hi").unwrap();
+        let mut rooms = store.rooms().unwrap();
+        rooms.sort();
+        assert_eq!(rooms, ["lobby", "other"]);
+
+        let author = Author {
+            pseudonym: String::from("Quiet Heron 3"),
+            nickname: Some(String::from("sam")),
+        };
+        let msg = store.new_message("lobby", Some(&author)).unwrap();
+        assert_eq!(msg.id, 1);
+        assert_eq!(msg.author.as_ref(), Some(&author));
+        assert!(msg.text.is_empty());
+        let edits = [
+            insert(0, "hello"),
+            Edit::Delete { at: 1, len: 3 },
+            insert(1, "i"),
+            Edit::Delete { at: 2, len: 1 },
+            insert(2, "🫠"),
+        ];
+        for edit in edits.clone() {
+            let keystroke = store
+                .push_edit("lobby", msg.id, edit.clone())
+                .unwrap()
+                .unwrap();
+            assert_eq!(keystroke.message_id, msg.id);
+            assert_eq!(keystroke.edit, edit);
+        }
+        assert!(
+            store
+                .push_edit("lobby", 9, insert(0, "x"))
+                .unwrap()
+                .is_none()
+        );
+
+        let messages = store.messages("lobby").unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].text, "welcome");
+        assert!(messages[0].finished);
+        assert_eq!(messages[1].text, "hi🫠");
+        assert!(!messages[1].finished);
+        assert_eq!(messages[0].author, None);
+        assert_eq!(messages[1].author, Some(author));
+        assert_eq!(store.messages("other").unwrap()[0].text, "hi");
+        store.delete_room("other").unwrap();
+        assert_eq!(store.rooms().unwrap(), ["lobby"]);
+        assert!(store.messages("other").unwrap().is_empty());
+        store.create_room("other", "hi again").unwrap();
+        assert_eq!(store.messages("other").unwrap()[0].text, "hi again");
+
+        let more = [
+            store.new_message("lobby", None).unwrap(),
+            store.new_message("lobby", None).unwrap(),
+        ];
+        let ids = |messages: Vec<Message>| -> Vec<u32> { messages.iter().map(|m| m.id).collect() };
+        assert_eq!(
+            ids(store.messages_before("lobby", None, 2).unwrap()),
+            [2, 3]
+        );
+        assert_eq!(
+            ids(store.messages_before("lobby", Some(2), 5).unwrap()),
+            [0, 1]
+        );
+        assert!(
+            store
+                .messages_before("lobby", Some(0), 5)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(ids(store.messages_since("lobby", 0, 2).unwrap()), [1, 2]);
+        assert!(store.messages_since("lobby", 3, 2).unwrap().is_empty());
+        assert!(
+            store
+                .messages_before("nowhere", None, 5)
+                .unwrap()
+                .is_empty()
+        );
+        for message in more {
+            store.finish_message("lobby", message.id).unwrap();
+        }
+
+        let keystrokes = store.keystrokes("lobby", msg.id).unwrap().unwrap();
+        let logged: Vec<Edit> = keystrokes
+            .iter()
+            .map(|keystroke| keystroke.edit.clone())
+            .collect();
+        assert_eq!(logged, edits);
+        assert!(
+            keystrokes
+                .windows(2)
+                .all(|pair| pair[0].time <= pair[1].time)
+        );
+        assert!(store.keystrokes("lobby", 0).unwrap().unwrap().is_empty());
+        assert!(store.keystrokes("lobby", 9).unwrap().is_none());
+        assert!(store.keystrokes("nowhere", 0).unwrap().is_none());
+
+        assert!(store.finish_message("lobby", msg.id).unwrap());
+        assert!(!store.finish_message("lobby", msg.id).unwrap());
+        assert!(!store.finish_message("lobby", 9).unwrap());
+        assert!(
+            store
+                .push_edit("lobby", msg.id, insert(0, "x"))
+                .unwrap()
+                .is_none()
+        );
+        let finished = &store.messages("lobby").unwrap()[1];
+        assert!(finished.finished);
+        assert_eq!(finished.text, "hi🫠");
+    }
+
+    /// Run the same eviction scenario against a store
+    fn exercise_limits(store: &dyn MessageStore) {
+        store.create_room("lobby", "welcome").unwrap();
+        let msg = store.new_message("lobby", None).unwrap();
+        let keystroke = store
+            .push_edit("lobby", msg.id, insert(0, "hello world"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(keystroke.edit, insert(0, "hello"));
+        assert!(
+            store
+                .push_edit("lobby", msg.id, insert(5, "!"))
+                .unwrap()
+                .is_none()
+        );
+        let keystroke = store
+            .push_edit("lobby", msg.id, Edit::Delete { at: 0, len: 1 })
+            .unwrap()
+            .unwrap();
+        assert_eq!(keystroke.edit, Edit::Delete { at: 0, len: 1 });
+        assert_eq!(store.messages("lobby").unwrap()[1].text, "ello");
+
+        assert!(store.evict("lobby").unwrap().is_empty());
+        store.finish_message("lobby", msg.id).unwrap();
+
+        // a flood of messages keeps the room at its newest few, and ids keep counting up
+        for expected_id in 2..50 {
+            let message = store.new_message("lobby", None).unwrap();
+            assert_eq!(message.id, expected_id);
+            store
+                .push_edit("lobby", message.id, insert(0, "abcde"))
+                .unwrap();
+            store.finish_message("lobby", message.id).unwrap();
+            store.evict("lobby").unwrap();
+        }
+        let ids: Vec<u32> = store
+            .messages("lobby")
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, [47, 48, 49]);
+        assert!(store.keystrokes("lobby", 2).unwrap().is_none());
+        assert_eq!(store.new_message("lobby", None).unwrap().id, 50);
+
+        // open messages are kept however old they get
+        for id in 51..54 {
+            store.new_message("lobby", None).unwrap();
+            store.finish_message("lobby", id).unwrap();
+            store.evict("lobby").unwrap();
+        }
+        let ids: Vec<u32> = store
+            .messages("lobby")
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, [50, 52, 53]);
+    }
+
+    /// Run the same byte limit scenario against a store
+    fn exercise_byte_limits(store: &dyn MessageStore) {
+        store.create_room("lobby", "welcome").unwrap();
+        for _ in 1..4 {
+            let message = store.new_message("lobby", None).unwrap();
+            store
+                .push_edit("lobby", message.id, insert(0, "abcd"))
+                .unwrap();
+        }
+        store.finish_message("lobby", 2).unwrap();
+        store.finish_message("lobby", 3).unwrap();
+        // 19 bytes: the oldest finished messages go until it fits in 10, and the open one stays
+        assert_eq!(store.evict("lobby").unwrap(), [0, 2]);
+        let ids: Vec<u32> = store
+            .messages("lobby")
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, [1, 3]);
+        assert!(store.keystrokes("lobby", 2).unwrap().is_none());
+        assert!(store.keystrokes("lobby", 1).unwrap().is_some());
+
+        // the newest is always kept
+        store
+            .create_room("big", "a welcome far too big for the room")
+            .unwrap();
+        assert!(store.evict("big").unwrap().is_empty());
+    }
+
+    const LIMITS: StoreLimits = StoreLimits {
+        max_message_chars: 5,
+        max_messages: 3,
+        max_room_bytes: 100,
+    };
+
+    #[test]
+    fn memory_store_limits() {
+        exercise_limits(&MemoryMessageStore::default().with_limits(LIMITS));
+    }
+
+    #[test]
+    fn sqlite_store_limits() {
+        exercise_limits(
+            &SqliteMessageStore::open(":memory:")
+                .unwrap()
+                .with_limits(LIMITS),
+        );
+    }
+
+    const BYTE_LIMITS: StoreLimits = StoreLimits {
+        max_messages: 100,
+        max_room_bytes: 10,
+        ..LIMITS
+    };
+
+    #[test]
+    fn memory_store_byte_limits() {
+        exercise_byte_limits(&MemoryMessageStore::default().with_limits(BYTE_LIMITS));
+    }
+
+    #[test]
+    fn sqlite_store_byte_limits() {
+        exercise_byte_limits(
+            &SqliteMessageStore::open(":memory:")
+                .unwrap()
+                .with_limits(BYTE_LIMITS),
+        );
+    }
+
+    #[test]
+    fn memory_store() {
+        exercise(&MemoryMessageStore::default());
+    }
+
+    #[test]
+    fn sqlite_store() {
+        exercise(&SqliteMessageStore::open(":memory:").unwrap());
+    }
+
+    #[test]
+    fn sqlite_store_persists() {
+        let path = temp_db("persists");
+        {
+            let store = SqliteMessageStore::open(&path).unwrap();
+            store.create_room("lobby", "welcome").unwrap();
+            let msg = store.new_message("lobby", None).unwrap();
+            store.push_edit("lobby", msg.id, insert(0, "a")).unwrap();
+        }
+        let store = SqliteMessageStore::open(&path).unwrap();
+        assert_eq!(store.rooms().unwrap(), ["lobby"]);
+        assert_eq!(store.messages("lobby").unwrap()[1].text, "a");
+        // nobody can still be typing a message from before the restart
+        assert!(store.messages("lobby").unwrap()[1].finished);
+        assert_eq!(store.keystrokes("lobby", 1).unwrap().unwrap().len(), 1);
+        drop(store);
+        remove_db(&path);
+    }
+
+    fn temp_db(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("cavalier-test-{name}-{}.db", std::process::id()))
+    }
+
+    fn remove_db(path: &std::path::Path) {
+        std::fs::remove_file(path).ok();
+        for suffix in ["-wal", "-shm"] {
+            let mut journal = path.as_os_str().to_owned();
+            journal.push(suffix);
+            std::fs::remove_file(journal).ok();
+        }
+    }
+}
@@ -0,0 +1,273 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Messages stored in RAM, securely deleted when the server restarts
+
+use super::{MessageStore, StoreError, StoreLimits};
+use cavalier_protocol::{Author, Edit, Keystroke, Message};
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+use std::time::Instant;
+
+/// A message along with the timed keystrokes that typed it
+struct StoredMessage {
+    message: Message,
+    created: Instant,
+    keystrokes: Vec<Keystroke>,
+}
+
+impl StoredMessage {
+    fn new(message: Message) -> Self {
+        StoredMessage {
+            message,
+            created: Instant::now(),
+            keystrokes: Vec::new(),
+        }
+    }
+}
+
+/// A room's messages, ordered by id, the id of the next one, and how many bytes of text they hold
+struct RoomMessages {
+    messages: VecDeque<StoredMessage>,
+    next_id: u32,
+    bytes: usize,
+}
+
+impl RoomMessages {
+    fn get(&self, id: u32) -> Option<&StoredMessage> {
+        let index = self
+            .messages
+            .binary_search_by_key(&id, |stored| stored.message.id)
+            .ok()?;
+        self.messages.get(index)
+    }
+
+    fn get_mut(&mut self, id: u32) -> Option<&mut StoredMessage> {
+        let index = self
+            .messages
+            .binary_search_by_key(&id, |stored| stored.message.id)
+            .ok()?;
+        self.messages.get_mut(index)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryMessageStore {
+    rooms: RwLock<HashMap<String, RoomMessages>>,
+    limits: StoreLimits,
+}
+
+impl MemoryMessageStore {
+    pub fn with_limits(self, limits: StoreLimits) -> Self {
+        MemoryMessageStore { limits, ..self }
+    }
+}
+
+impl MessageStore for MemoryMessageStore {
+    fn rooms(&self) -> Result<Vec<String>, StoreError> {
+        let rooms = self.rooms.read().map_err(|_| StoreError::Poisoned)?;
+        Ok(rooms.keys().cloned().collect())
+    }
+
+    fn create_room(&self, room: &str, welcome: &str) -> Result<(), StoreError> {
+        let mut rooms = self.rooms.write().map_err(|_| StoreError::Poisoned)?;
+        rooms
+            .entry(room.to_string())
+            .or_insert_with(|| RoomMessages {
+                messages: VecDeque::from([StoredMessage::new(Message {
+                    id: 0,
+                    text: String::from(welcome),
+                    finished: true,
+                    author: None,
+                })]),
+                next_id: 1,
+                bytes: welcome.len(),
+            });
+        Ok(())
+    }
+
+    fn delete_room(&self, room: &str) -> Result<(), StoreError> {
+        let mut rooms = self.rooms.write().map_err(|_| StoreError::Poisoned)?;
+        rooms.remove(room);
+        Ok(())
+    }
+
+    fn new_message(&self, room: &str, author: Option<&Author>) -> Result<Message, StoreError> {
+        let mut rooms = self.rooms.write().map_err(|_| StoreError::Poisoned)?;
+        let msgs = rooms
+            .entry(room.to_string())
+            .or_insert_with(|| RoomMessages {
+                messages: VecDeque::new(),
+                next_id: 0,
+                bytes: 0,
+            });
+        let id = msgs.next_id;
+        msgs.next_id = id.checked_add(1).ok_or(StoreError::IdOverflow)?;
+        let message = Message {
+            id,
+            text: String::new(),
+            finished: false,
+            author: author.cloned(),
+        };
+        msgs.messages.push_back(StoredMessage::new(message.clone()));
+        Ok(message)
+    }
+
+    #[cfg(test)]
+    fn messages(&self, room: &str) -> Result<Vec<Message>, StoreError> {
+        let rooms = self.rooms.read().map_err(|_| StoreError::Poisoned)?;
+        Ok(rooms
+            .get(room)
+            .map(|msgs| {
+                msgs.messages
+                    .iter()
+                    .map(|stored| stored.message.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn messages_before(
+        &self,
+        room: &str,
+        before: Option<u32>,
+        limit: usize,
+    ) -> Result<Vec<Message>, StoreError> {
+        let rooms = self.rooms.read().map_err(|_| StoreError::Poisoned)?;
+        let Some(msgs) = rooms.get(room) else {
+            return Ok(Vec::new());
+        };
+        let mut page: Vec<Message> = msgs
+            .messages
+            .iter()
+            .rev()
+            .map(|stored| &stored.message)
+            .filter(|message| before.is_none_or(|before| message.id < before))
+            .take(limit)
+            .cloned()
+            .collect();
+        page.reverse();
+        Ok(page)
+    }
+
+    fn messages_since(
+        &self,
+        room: &str,
+        since: u32,
+        limit: usize,
+    ) -> Result<Vec<Message>, StoreError> {
+        let rooms = self.rooms.read().map_err(|_| StoreError::Poisoned)?;
+        Ok(rooms
+            .get(room)
+            .map(|msgs| {
+                msgs.messages
+                    .iter()
+                    .map(|stored| &stored.message)
+                    .filter(|message| message.id > since)
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn push_edit(
+        &self,
+        room: &str,
+        message_id: u32,
+        edit: Edit,
+    ) -> Result<Option<Keystroke>, StoreError> {
+        let mut rooms = self.rooms.write().map_err(|_| StoreError::Poisoned)?;
+        let Some(msgs) = rooms.get_mut(room) else {
+            return Ok(None);
+        };
+        let Some(stored) = msgs
+            .get_mut(message_id)
+            .filter(|stored| !stored.message.finished)
+        else {
+            return Ok(None);
+        };
+        let Some(edit) = edit.fit(&stored.message.text, self.limits.max_message_chars) else {
+            return Ok(None);
+        };
+        let old_len = stored.message.text.len();
+        edit.apply(&mut stored.message.text);
+        let new_len = stored.message.text.len();
+        let keystroke = Keystroke {
+            message_id,
+            edit,
+            time: stored.created.elapsed(),
+        };
+        stored.keystrokes.push(keystroke.clone());
+        msgs.bytes = msgs.bytes - old_len + new_len;
+        Ok(Some(keystroke))
+    }
+
+    fn keystrokes(
+        &self,
+        room: &str,
+        message_id: u32,
+    ) -> Result<Option<Vec<Keystroke>>, StoreError> {
+        let rooms = self.rooms.read().map_err(|_| StoreError::Poisoned)?;
+        Ok(rooms
+            .get(room)
+            .and_then(|msgs| msgs.get(message_id))
+            .map(|stored| stored.keystrokes.clone()))
+    }
+
+    fn finish_message(&self, room: &str, message_id: u32) -> Result<bool, StoreError> {
+        let mut rooms = self.rooms.write().map_err(|_| StoreError::Poisoned)?;
+        let Some(stored) = rooms
+            .get_mut(room)
+            .and_then(|msgs| msgs.get_mut(message_id))
+        else {
+            return Ok(false);
+        };
+        let was_open = !stored.message.finished;
+        stored.message.finished = true;
+        Ok(was_open)
+    }
+
+    fn evict(&self, room: &str) -> Result<Vec<u32>, StoreError> {
+        let mut rooms = self.rooms.write().map_err(|_| StoreError::Poisoned)?;
+        let Some(msgs) = rooms.get_mut(room) else {
+            return Ok(Vec::new());
+        };
+        // drop the oldest finished messages until it fits, keeping open ones and the newest
+        let mut evicted = Vec::new();
+        let mut open = Vec::new();
+        while msgs.messages.len() > 1
+            && (msgs.messages.len() + open.len() > self.limits.max_messages
+                || msgs.bytes > self.limits.max_room_bytes)
+        {
+            let Some(oldest) = msgs.messages.pop_front() else {
+                break;
+            };
+            if oldest.message.finished {
+                msgs.bytes -= oldest.message.text.len();
+                evicted.push(oldest.message.id);
+            } else {
+                open.push(oldest);
+            }
+        }
+        for stored in open.into_iter().rev() {
+            msgs.messages.push_front(stored);
+        }
+        Ok(evicted)
+    }
+}
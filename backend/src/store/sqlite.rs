@@ -0,0 +1,380 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Messages stored in an embedded SQLite database, so history survives restarts
+//!
+//! Message creation times are stored as unix milliseconds, and keystroke times as milliseconds
+//! since their message was created.
+//!
+//! Nobody can keep typing a message across a restart, so opening the database finishes every
+//! message that was left open.
+//!
+//! Keystrokes are logged in the `edits` table.
+
+use super::{MessageStore, StoreError, StoreLimits};
+use cavalier_protocol::{Author, Edit, Keystroke, Message};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    CREATE TABLE IF NOT EXISTS rooms (
+        name TEXT PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS messages (
+        room TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        created_ms INTEGER NOT NULL,
+        finished INTEGER NOT NULL DEFAULT 0,
+        author TEXT,
+        nickname TEXT,
+        PRIMARY KEY (room, id)
+    );
+    CREATE TABLE IF NOT EXISTS edits (
+        room TEXT NOT NULL,
+        message_id INTEGER NOT NULL,
+        op TEXT NOT NULL,
+        at INTEGER NOT NULL,
+        len INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        time_ms INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS edits_by_message ON edits (room, message_id);
+";
+
+pub struct SqliteMessageStore {
+    conn: Mutex<Connection>,
+    limits: StoreLimits,
+}
+
+impl SqliteMessageStore {
+    /// Open (or create) the database at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        conn.execute("UPDATE messages SET finished = 1 WHERE finished = 0", [])?;
+        Ok(SqliteMessageStore {
+            conn: Mutex::new(conn),
+            limits: StoreLimits::default(),
+        })
+    }
+
+    pub fn with_limits(self, limits: StoreLimits) -> Self {
+        SqliteMessageStore { limits, ..self }
+    }
+
+    fn conn(&self) -> Result<std::sync::MutexGuard<'_, Connection>, StoreError> {
+        self.conn.lock().map_err(|_| StoreError::Poisoned)
+    }
+}
+
+fn now_ms() -> i64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    i64::try_from(since_epoch.as_millis()).unwrap_or(i64::MAX)
+}
+
+/// An edit from a row of `SELECT op, at, len, text`
+fn edit_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Edit> {
+    let op: String = row.get(0)?;
+    let at = row.get(1)?;
+    if op == "delete" {
+        Ok(Edit::Delete {
+            at,
+            len: row.get(2)?,
+        })
+    } else {
+        Ok(Edit::Insert {
+            at,
+            text: row.get(3)?,
+        })
+    }
+}
+
+/// The columns of an `edits` row: op, at, len, text
+fn edit_to_row(edit: &Edit) -> (&'static str, u32, u32, &str) {
+    match edit {
+        Edit::Insert { at, text } => ("insert", *at, 0, text),
+        Edit::Delete { at, len } => ("delete", *at, *len, ""),
+    }
+}
+
+/// A message from a row of `SELECT id, text, finished, author, nickname`
+fn message_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Message> {
+    let pseudonym: Option<String> = row.get(3)?;
+    Ok(Message {
+        id: row.get(0)?,
+        text: row.get(1)?,
+        finished: row.get(2)?,
+        author: match pseudonym {
+            Some(pseudonym) => Some(Author {
+                pseudonym,
+                nickname: row.get(4)?,
+            }),
+            None => None,
+        },
+    })
+}
+
+impl MessageStore for SqliteMessageStore {
+    fn rooms(&self) -> Result<Vec<String>, StoreError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT name FROM rooms ORDER BY name")?;
+        let rooms = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(rooms)
+    }
+
+    fn create_room(&self, room: &str, welcome: &str) -> Result<(), StoreError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let created = tx.execute("INSERT OR IGNORE INTO rooms (name) VALUES (?1)", [room])?;
+        if created > 0 {
+            tx.execute(
+                "INSERT INTO messages (room, id, text, created_ms, finished) VALUES (?1, 0, ?2, ?3, 1)",
+                params![room, welcome, now_ms()],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_room(&self, room: &str) -> Result<(), StoreError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        for table in [
+            "DELETE FROM edits WHERE room = ?1",
+            "DELETE FROM messages WHERE room = ?1",
+            "DELETE FROM rooms WHERE name = ?1",
+        ] {
+            tx.execute(table, [room])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn new_message(&self, room: &str, author: Option<&Author>) -> Result<Message, StoreError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let next_id: i64 = tx.query_row(
+            "SELECT COALESCE(MAX(id) + 1, 0) FROM messages WHERE room = ?1",
+            [room],
+            |row| row.get(0),
+        )?;
+        let id = u32::try_from(next_id).map_err(|_| StoreError::IdOverflow)?;
+        tx.execute(
+            "INSERT INTO messages (room, id, text, created_ms, author, nickname) VALUES (?1, ?2, '', ?3, ?4, ?5)",
+            params![
+                room,
+                id,
+                now_ms(),
+                author.map(|author| &author.pseudonym),
+                author.and_then(|author| author.nickname.as_ref()),
+            ],
+        )?;
+        tx.commit()?;
+        Ok(Message {
+            id,
+            text: String::new(),
+            finished: false,
+            author: author.cloned(),
+        })
+    }
+
+    #[cfg(test)]
+    fn messages(&self, room: &str) -> Result<Vec<Message>, StoreError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, text, finished, author, nickname FROM messages WHERE room = ?1 ORDER BY id",
+        )?;
+        let messages = stmt
+            .query_map([room], message_from_row)?
+            .collect::<Result<Vec<Message>, _>>()?;
+        Ok(messages)
+    }
+
+    fn messages_before(
+        &self,
+        room: &str,
+        before: Option<u32>,
+        limit: usize,
+    ) -> Result<Vec<Message>, StoreError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, text, finished, author, nickname FROM messages WHERE room = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+        )?;
+        let before = before.map_or(i64::MAX, i64::from);
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let mut messages = stmt
+            .query_map(params![room, before, limit], message_from_row)?
+            .collect::<Result<Vec<Message>, _>>()?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn messages_since(
+        &self,
+        room: &str,
+        since: u32,
+        limit: usize,
+    ) -> Result<Vec<Message>, StoreError> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, text, finished, author, nickname FROM messages WHERE room = ?1 AND id > ?2 ORDER BY id LIMIT ?3",
+        )?;
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let messages = stmt
+            .query_map(params![room, since, limit], message_from_row)?
+            .collect::<Result<Vec<Message>, _>>()?;
+        Ok(messages)
+    }
+
+    fn push_edit(
+        &self,
+        room: &str,
+        message_id: u32,
+        edit: Edit,
+    ) -> Result<Option<Keystroke>, StoreError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let open: Option<(String, i64)> = tx
+            .query_row(
+                "SELECT text, created_ms FROM messages WHERE room = ?1 AND id = ?2 AND finished = 0",
+                params![room, message_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((mut text, created_ms)) = open else {
+            return Ok(None);
+        };
+        let Some(edit) = edit.fit(&text, self.limits.max_message_chars) else {
+            return Ok(None);
+        };
+        let time_ms = u64::try_from(now_ms() - created_ms).unwrap_or(0);
+        edit.apply(&mut text);
+        tx.execute(
+            "UPDATE messages SET text = ?3 WHERE room = ?1 AND id = ?2",
+            params![room, message_id, text],
+        )?;
+        let (op, at, len, inserted) = edit_to_row(&edit);
+        tx.execute(
+            "INSERT INTO edits (room, message_id, op, at, len, text, time_ms) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![room, message_id, op, at, len, inserted, time_ms],
+        )?;
+        tx.commit()?;
+        Ok(Some(Keystroke {
+            message_id,
+            edit,
+            time: Duration::from_millis(time_ms),
+        }))
+    }
+
+    fn keystrokes(
+        &self,
+        room: &str,
+        message_id: u32,
+    ) -> Result<Option<Vec<Keystroke>>, StoreError> {
+        let conn = self.conn()?;
+        let exists: Option<u32> = conn
+            .query_row(
+                "SELECT id FROM messages WHERE room = ?1 AND id = ?2",
+                params![room, message_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if exists.is_none() {
+            return Ok(None);
+        }
+        let mut stmt = conn.prepare(
+            "SELECT op, at, len, text, time_ms FROM edits WHERE room = ?1 AND message_id = ?2 ORDER BY rowid",
+        )?;
+        let keystrokes = stmt
+            .query_map(params![room, message_id], |row| {
+                Ok(Keystroke {
+                    message_id,
+                    edit: edit_from_row(row)?,
+                    time: Duration::from_millis(row.get(4)?),
+                })
+            })?
+            .collect::<Result<Vec<Keystroke>, _>>()?;
+        Ok(Some(keystrokes))
+    }
+
+    fn finish_message(&self, room: &str, message_id: u32) -> Result<bool, StoreError> {
+        let conn = self.conn()?;
+        let finished = conn.execute(
+            "UPDATE messages SET finished = 1 WHERE room = ?1 AND id = ?2 AND finished = 0",
+            params![room, message_id],
+        )?;
+        Ok(finished > 0)
+    }
+
+    fn evict(&self, room: &str) -> Result<Vec<u32>, StoreError> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let (mut count, mut bytes, newest): (usize, usize, Option<u32>) = tx.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(length(CAST(text AS BLOB))), 0), MAX(id) FROM messages WHERE room = ?1",
+            [room],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        let fits =
+            |count, bytes| count <= self.limits.max_messages && bytes <= self.limits.max_room_bytes;
+        let Some(newest) = newest.filter(|_| !fits(count, bytes)) else {
+            return Ok(Vec::new());
+        };
+        // walk the oldest finished messages, keeping the newest, only as far as it takes to fit
+        let mut last = None;
+        {
+            let mut stmt = tx.prepare(
+                "SELECT id, length(CAST(text AS BLOB)) FROM messages WHERE room = ?1 AND finished = 1 AND id < ?2 ORDER BY id",
+            )?;
+            let mut rows = stmt.query(params![room, newest])?;
+            while !fits(count, bytes) {
+                let Some(row) = rows.next()? else {
+                    break;
+                };
+                last = Some(row.get::<_, u32>(0)?);
+                count -= 1;
+                bytes -= row.get::<_, usize>(1)?;
+            }
+        }
+        let Some(last) = last else {
+            return Ok(Vec::new());
+        };
+        tx.execute(
+            "DELETE FROM edits WHERE room = ?1 AND message_id IN (SELECT id FROM messages WHERE room = ?1 AND finished = 1 AND id <= ?2)",
+            params![room, last],
+        )?;
+        let mut evicted = {
+            let mut stmt = tx.prepare(
+                "DELETE FROM messages WHERE room = ?1 AND finished = 1 AND id <= ?2 RETURNING id",
+            )?;
+            stmt.query_map(params![room, last], |row| row.get(0))?
+                .collect::<Result<Vec<u32>, _>>()?
+        };
+        tx.commit()?;
+        evicted.sort_unstable();
+        Ok(evicted)
+    }
+}
@@ -0,0 +1,701 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Websocket handlers
+//!
+//! `/api/ws` is one socket carrying everything a client needs: the room's events and keystrokes,
+//! in the order they happened, and the edits the client types (see `cavalier_protocol::socket`).
+//! The older `/ws/events` and `/ws/key` sockets split these in two, and are only served while
+//! `legacy_sockets` is on.
+//!
+//! Each batch of edits on `/api/ws` is acked once it has been stored, after the keystrokes it made,
+//! so clients can echo what they type right away and settle it when the ack comes. A batch that
+//! can't be stored closes the socket instead. A client that opens it with `?echo=false` isn't sent
+//! the keystrokes typed on that socket.
+//!
+//! Every socket runs until the client closes it or something goes wrong. Every way a socket can go
+//! wrong is a `WsError`, which is logged and sent to the client as a close frame with a code and a
+//! reason, so nothing in here panics or leaves a socket hanging.
+//!
+//! A socket typing faster than its rate limits allow is closed with a policy violation.
+//!
+//! A socket that falls behind its room's broadcast channel is sent an `Event::Resync` and carries
+//! on. The legacy sockets are sent it as text, even the key socket.
+//!
+//! When a socket ends, its ping task is stopped. When a socket that types ends, the message its
+//! session was typing is finished unless the session reconnects within a grace period. While an
+//! `/api/ws` or events socket is open, its session is present in the room.
+
+use crate::AppState;
+use crate::authors;
+use crate::limits::{ClientIp, RateLimits};
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::rooms::{CurrentRoom, Room, SocketId, Update};
+use crate::shutdown;
+use axum::{
+    extract::{
+        Query, State, WebSocketUpgrade,
+        ws::{self, CloseFrame, WebSocket, close_code},
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use cavalier_protocol::{
+    Author, ClientFrame, Edit, Event, ServerFrame,
+    frame::{self, FrameError},
+};
+use futures_util::{
+    sink::SinkExt,
+    stream::{SplitSink, SplitStream, StreamExt},
+};
+use serde::Deserialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    sync::{
+        Mutex,
+        broadcast::{Receiver, error::RecvError},
+        mpsc,
+    },
+    task::JoinHandle,
+    time::{Duration, Instant, interval},
+};
+use tower_sessions::{Session, session::Id as SessionId};
+use tracing::{Instrument, Span, field::Empty};
+
+/// The sending half of a websocket, shared between the ping task and the handler
+type SharedSink = Arc<Mutex<SplitSink<WebSocket, ws::Message>>>;
+
+/// Why a websocket was closed by the server
+#[derive(Debug)]
+pub enum WsError {
+    /// The socket was opened without a session, so its keystrokes can't belong to a message
+    NoSession,
+    /// The client typed before it ever had a message to type into
+    NoActiveMessage,
+    /// The client typed faster than its rate limits allow
+    RateLimited,
+    /// A binary frame could not be decoded
+    BadFrame(FrameError),
+    /// The client sent a kind of frame this socket does not accept
+    UnexpectedFrame,
+    /// The client fell too far behind the room's broadcast channel, and couldn't be resynced
+    Lagged(u64),
+    /// The room's broadcast channel is gone
+    ChannelClosed,
+    /// An event could not be serialized
+    Encode(serde_json::Error),
+    /// The client's edits could not be stored, so the client's copy of its message is no longer
+    /// the server's
+    Store,
+    /// Reading from or writing to the socket failed
+    Socket(axum::Error),
+    /// The server is shutting down
+    ShuttingDown,
+}
+
+impl WsError {
+    /// The close code sent to the client
+    fn close_code(&self) -> u16 {
+        match self {
+            WsError::NoSession | WsError::NoActiveMessage | WsError::RateLimited => {
+                close_code::POLICY
+            }
+            WsError::BadFrame(_) => close_code::INVALID,
+            WsError::UnexpectedFrame => close_code::UNSUPPORTED,
+            WsError::ChannelClosed | WsError::ShuttingDown => close_code::AWAY,
+            WsError::Lagged(_) | WsError::Encode(_) | WsError::Store | WsError::Socket(_) => {
+                close_code::ERROR
+            }
+        }
+    }
+}
+
+impl fmt::Display for WsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WsError::NoSession => write!(f, "no session"),
+            WsError::NoActiveMessage => write!(f, "no active message"),
+            WsError::RateLimited => write!(f, "typing too fast"),
+            WsError::BadFrame(e) => write!(f, "bad frame: {e}"),
+            WsError::UnexpectedFrame => write!(f, "unexpected frame type"),
+            WsError::Lagged(missed) => write!(f, "fell behind by {missed} updates"),
+            WsError::ChannelClosed => write!(f, "room closed"),
+            WsError::Encode(e) => write!(f, "could not encode event: {e}"),
+            WsError::Store => write!(f, "could not store keystrokes"),
+            WsError::Socket(e) => write!(f, "socket error: {e}"),
+            WsError::ShuttingDown => write!(f, "server shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for WsError {}
+
+impl From<FrameError> for WsError {
+    fn from(e: FrameError) -> Self {
+        WsError::BadFrame(e)
+    }
+}
+
+impl From<axum::Error> for WsError {
+    fn from(e: axum::Error) -> Self {
+        WsError::Socket(e)
+    }
+}
+
+/// Ping the client every `period` until the socket fails. Abort the task when the socket ends.
+fn spawn_ping_task(sender: SharedSink, period: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = sender
+                .lock()
+                .await
+                .send(ws::Message::Ping(Bytes::from_static(&[8u8])))
+                .await
+            {
+                tracing::debug!(error = %e, "ping failed");
+                break;
+            }
+        }
+    })
+}
+
+/// The span a websocket's handler runs in, for as long as the socket is open
+fn socket_span(endpoint: &'static str, room: &Room, session_id: &SessionId) -> Span {
+    tracing::info_span!(
+        "ws",
+        endpoint,
+        room = %room.name,
+        session_id = %logging::session_tag(session_id),
+        message_id = Empty,
+    )
+}
+
+/// Log why a socket ended, and tell the client with a close frame if it is still there
+async fn close(sender: &SharedSink, result: Result<(), WsError>) {
+    let Err(e) = result else {
+        tracing::info!("socket closed by client");
+        return;
+    };
+    tracing::info!(error = %e, "closing socket");
+    if matches!(e, WsError::Socket(_)) {
+        return; // nobody to tell
+    }
+    let frame = CloseFrame {
+        code: e.close_code(),
+        reason: e.to_string().into(),
+    };
+    if let Err(e) = sender
+        .lock()
+        .await
+        .send(ws::Message::Close(Some(frame)))
+        .await
+    {
+        tracing::debug!(error = %e, "could not send close frame");
+    }
+}
+
+/// The answer to a socket opened while the server is shutting down
+fn shutting_down_response() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "The server is shutting down",
+    )
+        .into_response()
+}
+
+/// The session's author, or the answer to give if it can't be read
+async fn socket_author(session: &Session) -> Result<Author, Response> {
+    authors::session_author(session).await.map_err(|e| {
+        tracing::error!(error = %e, "could not get session author");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Could not access session, try again.",
+        )
+            .into_response()
+    })
+}
+
+/// Wait for the next update to the room. If the socket fell behind, the update is a resync.
+async fn recv_update(
+    update_rx: &mut Receiver<Update>,
+    room: &Room,
+    endpoint: &str,
+    metrics: &Metrics,
+) -> Result<Update, WsError> {
+    match update_rx.recv().await {
+        Ok(update) => Ok(update),
+        Err(RecvError::Lagged(missed)) => {
+            metrics.broadcast_lagged(endpoint, missed);
+            resync_event(room, missed).await.map(Update::Event)
+        }
+        Err(RecvError::Closed) => Err(WsError::ChannelClosed),
+    }
+}
+
+/// The `Event::Resync` for a client that missed `missed` updates. If there is no way to catch it
+/// up, the socket has to close.
+async fn resync_event(room: &Room, missed: u64) -> Result<Event, WsError> {
+    tracing::warn!(missed, "client fell behind, resyncing");
+    match room.resync_snapshot().await {
+        Ok(messages) => Ok(Event::Resync { messages }),
+        Err(e) => {
+            tracing::error!(error = %e, "could not make resync snapshot");
+            Err(WsError::Lagged(missed))
+        }
+    }
+}
+
+/// A socket its session types into its message through
+struct Typist<'a> {
+    room: &'a Room,
+    session_id: SessionId,
+    ip: IpAddr,
+    socket_id: SocketId,
+    /// The message this socket last typed into, if it ever had one
+    last_message: Option<u32>,
+}
+
+impl<'a> Typist<'a> {
+    fn new(room: &'a Room, session_id: SessionId, ip: IpAddr) -> Self {
+        Typist {
+            room,
+            session_id,
+            ip,
+            socket_id: next_socket_id(),
+            last_message: None,
+        }
+    }
+
+    /// Apply, store and relay a batch of edits the client typed into its session's message. Edits
+    /// past the end of a full or finished message are dropped, but failing to store one is an
+    /// error.
+    async fn type_edits(
+        &mut self,
+        edits: Vec<Edit>,
+        limits: &RateLimits,
+        metrics: &Metrics,
+    ) -> Result<(), WsError> {
+        let room = self.room;
+        if !limits.allow_edits(self.session_id, self.ip, edits.len()) {
+            return Err(WsError::RateLimited);
+        }
+
+        let message_id = {
+            let mut session_to_message = room.session_to_message.write().await;
+            match session_to_message.get_mut(&self.session_id) {
+                Some(open) => {
+                    open.last_active = Instant::now();
+                    open.id
+                }
+                // the message was finished (by Send or for idling) and the next one isn't made
+                // yet, so the edits have nowhere to go
+                None if self.last_message.is_some() => return Ok(()),
+                None => return Err(WsError::NoActiveMessage),
+            }
+        };
+        if self.last_message != Some(message_id) {
+            self.last_message = Some(message_id);
+            Span::current().record("message_id", message_id);
+        }
+
+        // apply, stamp and store each edit before relaying it, so the text and timing clients
+        // see match the store
+        for edit in edits {
+            let keystroke = match room.store.push_edit(&room.name, message_id, edit) {
+                Ok(Some(keystroke)) => keystroke,
+                Ok(None) => continue, // finished since it was looked up, or full
+                // the client can't be told which edits were kept, so it has to start over
+                Err(e) => {
+                    tracing::error!(error = %e, "could not store keystroke");
+                    return Err(WsError::Store);
+                }
+            };
+            match room
+                .update_tx
+                .send(Update::Keystroke(keystroke, self.socket_id))
+            {
+                Ok(_) => metrics.keystrokes_relayed.inc(),
+                Err(e) => tracing::debug!(error = %e, "could not broadcast keystroke"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A new id for a socket, never given out before
+fn next_socket_id() -> SocketId {
+    static NEXT_SOCKET_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_SOCKET_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/*************\
+* Socket Code *
+\*************/
+
+/// Options a client picks when it opens `/api/ws`, as query parameters
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct SocketOptions {
+    /// Whether keystrokes typed on the socket are sent back to it along with everyone else's
+    echo: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions { echo: true }
+    }
+}
+
+pub async fn socket_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    CurrentRoom(room): CurrentRoom,
+    Query(options): Query<SocketOptions>,
+    session: Session,
+) -> Response {
+    if shutdown::shutting_down(&state.shutdown) {
+        return shutting_down_response();
+    }
+    session.insert("preserve", true).await.ok(); // ensures session
+    let Some(session_id) = session.id() else {
+        return (StatusCode::BAD_REQUEST, "Session id is not set!").into_response();
+    };
+    let author = match socket_author(&session).await {
+        Ok(author) => author,
+        Err(response) => return response,
+    };
+    let span = socket_span("/ws", &room, &session_id);
+    ws.on_upgrade(move |ws| {
+        ws_socket_handler(ws, state, room, session_id, author, ip, options).instrument(span)
+    })
+}
+
+/// Relay the room to the client and the client's edits to the room until either side ends, with
+/// the session present in the room and typing its message meanwhile
+async fn ws_socket_handler(
+    ws: WebSocket,
+    state: AppState,
+    room: Arc<Room>,
+    session_id: SessionId,
+    author: Author,
+    ip: IpAddr,
+    options: SocketOptions,
+) {
+    let (sender, receiver) = ws.split();
+    let sender: SharedSink = Arc::new(Mutex::new(sender));
+    let ping_task = spawn_ping_task(sender.clone(), state.config.ping_interval());
+    let _connected = state.metrics.socket_connected("/ws");
+    tracing::info!(echo = options.echo, "socket opened");
+
+    // subscribe before joining, so the client hears about itself
+    let update_rx = room.update_tx.subscribe();
+    room.join(session_id, author).await;
+    // a reconnecting author picks up the message they were typing
+    room.connect_session(&session_id).await;
+
+    let typist = Typist::new(&room, session_id, ip);
+    // the own keystrokes to leave out, if the client doesn't want them
+    let own = (!options.echo).then_some(typist.socket_id);
+    let (ack_tx, ack_rx) = mpsc::unbounded_channel();
+    // while shutting down, the room is drained and says goodbye, which ends the sending half
+    let result = tokio::select! {
+        result = ws_socket_send(&sender, &room, update_rx, ack_rx, own, &state.metrics) => result,
+        result = ws_socket_recv(receiver, typist, ack_tx, &state.limits, &state.metrics) => result,
+    };
+
+    ping_task.abort();
+    room.leave(&session_id).await;
+    // if that was the author's last socket, their message is finished unless they come back soon
+    room.disconnect_session(&session_id).await;
+    close(&sender, result).await;
+}
+
+/// Relay every update to the room to the client in order, except keystrokes typed on the socket
+/// `own`, and ack the client's edits
+async fn ws_socket_send(
+    sender: &SharedSink,
+    room: &Room,
+    mut update_rx: Receiver<Update>,
+    mut ack_rx: mpsc::UnboundedReceiver<u32>,
+    own: Option<SocketId>,
+    metrics: &Metrics,
+) -> Result<(), WsError> {
+    loop {
+        // A batch's keystrokes are broadcast before it is acked, so checking the room first keeps
+        // them in front of the ack
+        let frame = tokio::select! {
+            biased;
+            update = recv_update(&mut update_rx, room, "/ws", metrics) => match update? {
+                Update::Event(event) => ServerFrame::Event(event),
+                Update::Keystroke(_, socket_id) if Some(socket_id) == own => continue,
+                Update::Keystroke(keystroke, _) => ServerFrame::Keystroke(keystroke),
+            },
+            Some(seq) = ack_rx.recv() => ServerFrame::Ack { seq },
+        };
+        let bytes = Bytes::from(frame.encode());
+        sender.lock().await.send(ws::Message::Binary(bytes)).await?;
+        if frame == ServerFrame::Event(Event::ServerShuttingDown) {
+            return Err(WsError::ShuttingDown);
+        }
+    }
+}
+
+/// Read the client's frames until it closes the socket, acking each batch of edits once it has
+/// been typed
+async fn ws_socket_recv(
+    mut receiver: SplitStream<WebSocket>,
+    mut typist: Typist<'_>,
+    ack_tx: mpsc::UnboundedSender<u32>,
+    limits: &RateLimits,
+    metrics: &Metrics,
+) -> Result<(), WsError> {
+    while let Some(msg) = receiver.next().await {
+        let body = match msg? {
+            ws::Message::Binary(body) => body,
+            ws::Message::Close(_) => return Ok(()),
+            ws::Message::Ping(_) | ws::Message::Pong(_) => continue,
+            ws::Message::Text(_) => return Err(WsError::UnexpectedFrame),
+        };
+        match ClientFrame::decode(&body)? {
+            ClientFrame::Edits { seq, edits } => {
+                typist.type_edits(edits, limits, metrics).await?;
+                // the sending half outlives this one, so the ack has somewhere to go
+                ack_tx.send(seq).ok();
+            }
+        }
+    }
+    Ok(())
+}
+
+/*******************\
+* Legacy Event Code *
+\*******************/
+
+pub async fn events_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    CurrentRoom(room): CurrentRoom,
+    session: Session,
+) -> Response {
+    if shutdown::shutting_down(&state.shutdown) {
+        return shutting_down_response();
+    }
+    session.insert("preserve", true).await.ok(); // ensures session
+    let Some(session_id) = session.id() else {
+        return (StatusCode::BAD_REQUEST, "Session id is not set!").into_response();
+    };
+    let author = match socket_author(&session).await {
+        Ok(author) => author,
+        Err(response) => return response,
+    };
+    let span = socket_span("/ws/events", &room, &session_id);
+    ws.on_upgrade(move |ws| ws_events_handler(ws, state, room, session_id, author).instrument(span))
+}
+
+/// Send updates to the client live as `Event` jsons, with the session present in the room until
+/// the socket ends
+async fn ws_events_handler(
+    ws: WebSocket,
+    state: AppState,
+    room: Arc<Room>,
+    session_id: SessionId,
+    author: Author,
+) {
+    let (sender, mut receiver) = ws.split();
+    let sender: SharedSink = Arc::new(Mutex::new(sender));
+    let ping_task = spawn_ping_task(sender.clone(), state.config.ping_interval());
+    let _connected = state.metrics.socket_connected("/ws/events");
+    tracing::info!("socket opened");
+
+    // subscribe before joining, so the client hears about itself
+    let update_rx = room.update_tx.subscribe();
+    room.join(session_id, author).await;
+
+    // Always read from the socket to keep it alive and notice when it closes
+    let result = tokio::select! {
+        result = ws_events_recv(&mut receiver) => result,
+        result = ws_events_send(&sender, &room, update_rx, &state.metrics) => result,
+    };
+
+    ping_task.abort();
+    room.leave(&session_id).await;
+    close(&sender, result).await;
+}
+
+/// Read from the events socket until the client closes it. Clients never send events.
+async fn ws_events_recv(receiver: &mut SplitStream<WebSocket>) -> Result<(), WsError> {
+    while let Some(msg) = receiver.next().await {
+        match msg? {
+            ws::Message::Close(_) => return Ok(()),
+            ws::Message::Ping(_) | ws::Message::Pong(_) => {}
+            ws::Message::Text(_) | ws::Message::Binary(_) => return Err(WsError::UnexpectedFrame),
+        }
+    }
+    Ok(())
+}
+
+/// Relay the room's events to the client
+async fn ws_events_send(
+    sender: &SharedSink,
+    room: &Room,
+    mut update_rx: Receiver<Update>,
+    metrics: &Metrics,
+) -> Result<(), WsError> {
+    loop {
+        let event = match recv_update(&mut update_rx, room, "/ws/events", metrics).await? {
+            Update::Event(event) => event,
+            Update::Keystroke(..) => continue,
+        };
+        send_event(sender, &event).await?;
+        if event == Event::ServerShuttingDown {
+            return Err(WsError::ShuttingDown);
+        }
+    }
+}
+
+/// Send an event to the client as json
+async fn send_event(sender: &SharedSink, event: &Event) -> Result<(), WsError> {
+    let json = serde_json::to_string(event).map_err(WsError::Encode)?;
+    sender
+        .lock()
+        .await
+        .send(ws::Message::Text(json.into()))
+        .await?;
+    Ok(())
+}
+
+/*****************************************\
+* Legacy Client <-> Server Keystroke Code *
+\*****************************************/
+
+pub async fn key_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    CurrentRoom(room): CurrentRoom,
+    session: Session,
+) -> Response {
+    if shutdown::shutting_down(&state.shutdown) {
+        return shutting_down_response();
+    }
+    session.insert("preserve", true).await.ok(); // ensures session
+    let Some(session_id) = session.id() else {
+        return (StatusCode::BAD_REQUEST, "Session id is not set!").into_response();
+    };
+    let span = socket_span("/ws/key", &room, &session_id);
+    ws.on_upgrade(move |ws| ws_key_handler(ws, state, room, session, ip).instrument(span))
+}
+
+/// Split websocket into send/recv and run the client -> server and server -> client halves until
+/// either one ends
+async fn ws_key_handler(
+    ws: WebSocket,
+    state: AppState,
+    room: Arc<Room>,
+    session: Session,
+    ip: IpAddr,
+) {
+    let (sender, receiver) = ws.split();
+    let sender: SharedSink = Arc::new(Mutex::new(sender));
+
+    let Some(session_id) = session.id() else {
+        close(&sender, Err(WsError::NoSession)).await;
+        return;
+    };
+    let _connected = state.metrics.socket_connected("/ws/key");
+    tracing::info!("socket opened");
+
+    // a reconnecting author picks up the message they were typing
+    room.connect_session(&session_id).await;
+    let ping_task = spawn_ping_task(sender.clone(), state.config.ping_interval());
+    let mut shutdown = state.shutdown.clone();
+    let typist = Typist::new(&room, session_id, ip);
+    let result = tokio::select! {
+        _ = shutdown.wait_for(|shutting_down| *shutting_down) => Err(WsError::ShuttingDown),
+        result = ws_s2c(&sender, &room, &state.metrics) => result,
+        result = ws_c2s(receiver, typist, &state.limits, &state.metrics) => result,
+    };
+    ping_task.abort();
+
+    // if that was the author's last socket, their message is finished unless they come back soon
+    room.disconnect_session(&session_id).await;
+    close(&sender, result).await;
+}
+
+/***********************\
+* Server -> Client Code *
+\***********************/
+
+/// receive keystrokes from the room
+/// send keystrokes to all clients, including the originator. Only `/api/ws` can leave them out.
+async fn ws_s2c(sender: &SharedSink, room: &Room, metrics: &Metrics) -> Result<(), WsError> {
+    let mut update_rx = room.update_tx.subscribe();
+    loop {
+        let keystroke = match recv_update(&mut update_rx, room, "/ws/key", metrics).await? {
+            Update::Keystroke(keystroke, _) => keystroke,
+            // keystrokes can't fix the text once some are missing, so send the text itself
+            Update::Event(resync @ Event::Resync { .. }) => {
+                send_event(sender, &resync).await?;
+                continue;
+            }
+            Update::Event(_) => continue,
+        };
+        let msg_bytes = Bytes::copy_from_slice(&keystroke.encode());
+        sender
+            .lock()
+            .await
+            .send(ws::Message::Binary(msg_bytes))
+            .await?;
+    }
+}
+
+/***********************\
+* Client -> Server Code *
+\***********************/
+
+/// receive keystrokes from the client
+/// send keystrokes to the room
+async fn ws_c2s(
+    mut receiver: SplitStream<WebSocket>,
+    mut typist: Typist<'_>,
+    limits: &RateLimits,
+    metrics: &Metrics,
+) -> Result<(), WsError> {
+    while let Some(msg) = receiver.next().await {
+        let body = match msg? {
+            ws::Message::Binary(body) => body,
+            ws::Message::Close(_) => return Ok(()),
+            ws::Message::Ping(_) | ws::Message::Pong(_) => continue,
+            ws::Message::Text(_) => return Err(WsError::UnexpectedFrame),
+        };
+        // inserts and deletes at any position in the message, applied in order
+        let edits = frame::decode_edits(&body)?;
+        typist.type_edits(edits, limits, metrics).await?;
+    }
+    Ok(())
+}
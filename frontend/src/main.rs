@@ -21,21 +21,29 @@
 // (ws, json api, DOM methods, event listeners)
 // and features (chat, user, auth, moderation, voting, etc).
 
-// TODO: examine binary_serde for sending Keystroke struct
-
 // TODO: Reevaluate message creation timing.
 // Currently, a new message is created when the app loads and when Send is pressed. However, this
 // causes unexpected ordering of messages, as a user may press Send, wait, and then begin typing.
 // Instead, a new message should be created when the first keystroke of a new message is being
 // created.
-use js_sys::{ArrayBuffer, JsString, Uint8Array};
-use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use cavalier_protocol::{
+    Author, ClientFrame, Edit, Event, Keystroke, Message, Presence, RoomInfo, ServerFrame,
+    valid_room_name,
+};
+use js_sys::{ArrayBuffer, Promise, Uint8Array};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicU32, Ordering},
+};
+use std::time::Duration;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::{JsFuture, spawn_local};
 use web_sys::{
-    BinaryType, Element, HtmlElement, HtmlInputElement, MessageEvent, Request, RequestCredentials,
-    RequestInit, RequestMode, Response, WebSocket, window,
+    BinaryType, CloseEvent, Element, HtmlElement, HtmlInputElement, MessageEvent, Request,
+    RequestCredentials, RequestInit, RequestMode, Response, WebSocket, window,
 };
 
 macro_rules! console_log {
@@ -48,42 +56,6 @@ extern "C" {
     fn log(s: &str);
 }
 
-/***********************\
-* Global Structs, Enums *
-\***********************/
-// TODO: refactor: move these into shared crate
-
-/// A completed Message.
-///
-/// The text contains all keystrokes, including backspace.
-/// Timings will be added
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Message {
-    id: u32,
-    text: String,
-}
-
-/// A keystroke
-///
-/// Associates key char with message_id, timing, any other info.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Keystroke {
-    message_id: u32,
-    key: char,
-    /* time: std::time::Duration, */  //this will get added in when DB functionality is added
-}
-
-/// An event
-///
-/// Communicates from the server to the client that a new message has been created, a message is
-/// over, there is a new user, etc any live updates the client could want
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "event", content = "data")]
-enum Event {
-    MessageNew(Message),
-    MessageEnd,
-}
-
 #[wasm_bindgen(main)]
 fn main() -> Result<(), JsValue> {
     console_error_panic_hook::set_once();
@@ -98,170 +70,225 @@ fn main() -> Result<(), JsValue> {
 
 #[wasm_bindgen]
 pub async fn run() -> Result<(), JsValue> {
-    let msgvec: Vec<Message> = get_msg().await?;
+    // The room is picked by the URL path. Visiting a room that doesn't exist yet creates it.
+    let room = current_room();
+    if let Some(room) = &room {
+        let joined = new_room(room).await;
+        if let Err(err) = joined {
+            console_log!("Could not join room {}: {:?}", room, err);
+            window().unwrap().location().set_href("/")?;
+            return Ok(());
+        }
+    }
+    render_room_switcher(&get_rooms().await?, room.as_deref())?;
+
+    // Load the newest page of history, and older pages when the user scrolls to the top
+    let msgvec: Vec<Message> = get_msg(HistoryCursor::Latest).await?;
     for msg in &msgvec {
-        insert_message_div(msg.id, &msg.text);
+        insert_message_div(msg);
     }
+    let history = Rc::new(History {
+        oldest: Cell::new(msgvec.first().map(|msg| msg.id)),
+        more: Cell::new(msgvec.len() >= HISTORY_PAGE),
+        loading: Cell::new(false),
+    });
+    if !msg_cont_scrollable() {
+        let history = history.clone();
+        spawn_local(async move {
+            if let Err(err) = load_older_messages(history).await {
+                console_log!("Error loading older messages: {:?}", err);
+            }
+        });
+    }
+    let on_msg_cont_scroll = Closure::<dyn FnMut(_)>::new(move |_event: web_sys::Event| {
+        const LOAD_MARGIN: i32 = 50;
+        let near_top = window()
+            .and_then(|win| win.document())
+            .and_then(|doc| doc.get_element_by_id("messages-container"))
+            .is_some_and(|msg_cont| msg_cont.scroll_top() < LOAD_MARGIN);
+        if near_top {
+            let history = history.clone();
+            spawn_local(async move {
+                if let Err(err) = load_older_messages(history).await {
+                    console_log!("Error loading older messages: {:?}", err);
+                }
+            });
+        }
+    });
+    window()
+        .and_then(|win| win.document())
+        .and_then(|doc| doc.get_element_by_id("messages-container"))
+        .expect("Message container does not exist")
+        .add_event_listener_with_callback("scroll", on_msg_cont_scroll.as_ref().unchecked_ref())?;
+    on_msg_cont_scroll.forget();
 
     new_session().await?;
 
     let current_message: Arc<Mutex<Option<Message>>> = Arc::new(Mutex::new(None)); //global current message cursor
 
-    let ws_protocol = if window().unwrap().location().protocol()? == "https:" {
-        "wss:"
-    } else {
-        "ws:"
-    };
-    let hostname = window().unwrap().location().hostname()?;
-    let port = window().unwrap().location().port()?;
-
-    let ws_events_url = format!(
-        "{}//{}:{}{}",
-        &ws_protocol, &hostname, &port, "/api/ws/events"
-    );
+    let status = Rc::new(ConnectionStatus::new(1));
+    // the input's value as of the last edits sent for it
+    let old_val: Arc<Mutex<String>> = Arc::new(Mutex::new(String::with_capacity(10)));
+    let echo = Rc::new(LocalEcho::new(old_val.clone()));
 
-    // Create onmessage function for the events websocket. Currently, this simply looks for
-    // MessageNew events and adds a new message div to the DOM when one comes in.
-    let ws_events = WebSocket::new(&ws_events_url)?; //.expect("Binding to events websocket failed");
-    let ws_events_onmessage = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
-        match e.data().dyn_into::<JsString>() {
-            Ok(event_json) => {
-                let event_json_str = String::from(event_json);
-                let event: Event = serde_json::from_str(&event_json_str[..])
-                    .expect("Invalid event received from server");
-                update_msg_visibility();
-                match event {
-                    Event::MessageNew(message) => {
-                        // Create new div for the message
-                        console_log!("Message id from server: {}", message.id);
-                        insert_message_div(message.id, &message.text);
-                        scroll_msg_cont_to_bottom();
+    // The socket brings the room's events and keystrokes, in the order they happened, and takes
+    // the user's edits. What the user types is shown right away, and settled when the server acks
+    // it.
+    //
+    // The first time it opens, it makes the initial message that the user starts with. When it
+    // reopens after losing the connection, it catches up on whatever was missed instead.
+    let open_current_message_ref = current_message.clone();
+    let open_echo = echo.clone();
+    let socket_current_message_ref = current_message.clone();
+    let socket_status = status.clone();
+    let socket_echo = echo.clone();
+    let ws = ReconnectingSocket::new(
+        "Room",
+        ws_url("/ws")?,
+        Some(BinaryType::Arraybuffer),
+        status,
+        move |reconnect: bool| {
+            let cur_msg_ref = open_current_message_ref.clone();
+            let echo = open_echo.clone();
+            if reconnect {
+                echo.reconnected();
+            }
+            spawn_local(async move {
+                // presence events from before the socket opened were missed, so start over
+                match get_presence().await {
+                    Ok(presence) => render_presence(&presence),
+                    Err(err) => console_log!("Error getting presence: {:?}", err),
+                }
+                if reconnect {
+                    if let Err(err) = resume(&cur_msg_ref, &echo).await {
+                        console_log!("Error catching up after reconnecting: {:?}", err);
                     }
-                    Event::MessageEnd => {}
+                    return;
                 }
-            }
-            Err(e) => console_log!("Error receiving event: {:?}", e),
-        }
-    });
-    ws_events.set_onmessage(Some(ws_events_onmessage.as_ref().unchecked_ref()));
-    ws_events_onmessage.forget();
-
-    // Add onopen function which creates a new message when the websocket opens. This is the
-    // initial message that the user starts with.
-    let cur_msg_ref = current_message.clone();
-    let ws_events_onopen = Closure::<dyn FnMut(_)>::new(move |_e: web_sys::Event| {
-        let cur_msg_ref = cur_msg_ref.clone();
-        wasm_bindgen_futures::spawn_local(async move {
-            let new_msg: Message = new_msg().await.unwrap();
-            let mut cur_msg = cur_msg_ref.lock().expect("Couldn't set initial message");
-            (*cur_msg) = Some(new_msg);
-        })
-    });
-    ws_events.set_onopen(Some(ws_events_onopen.as_ref().unchecked_ref()));
-    ws_events_onopen.forget();
-
-    let ws_events_onclose = Closure::<dyn FnMut(_)>::new(move |_e: web_sys::Event| {
-        //window().unwrap().location().reload().unwrap()
-    });
-    ws_events.set_onclose(Some(ws_events_onclose.as_ref().unchecked_ref()));
-    ws_events_onclose.forget();
-
-    // Add onmessage function to websocket to listen for keystrokes and add them to the right
-    // message.
-    let ws_key_url = format!("{}//{}:{}{}", &ws_protocol, &hostname, &port, "/api/ws/key");
-    let ws_key = WebSocket::new(&ws_key_url)?;
-    ws_key.set_binary_type(BinaryType::Arraybuffer);
-    let ws_key_onmessage = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
-        match e.data().dyn_into::<ArrayBuffer>() {
+                let new_msg = new_msg_retrying().await;
+                echo.start(&new_msg);
+                let mut cur_msg = cur_msg_ref.lock().expect("Couldn't set initial message");
+                (*cur_msg) = Some(new_msg);
+            })
+        },
+        move |e: MessageEvent| match e.data().dyn_into::<ArrayBuffer>() {
             Ok(abuf) => {
-                let array = Uint8Array::new(&abuf);
-                let bytes = array.to_vec();
-                let key =
-                    char::from_u32(u32::from_le_bytes(bytes[0..4].try_into().unwrap())).unwrap();
-                let message_id = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
-                update_message_div(message_id, key);
-                update_msg_visibility();
+                let bytes = Uint8Array::new(&abuf).to_vec();
+                match ServerFrame::decode(&bytes) {
+                    Ok(ServerFrame::Event(event)) => handle_event(
+                        event,
+                        &socket_current_message_ref,
+                        &socket_status,
+                        &socket_echo,
+                    ),
+                    Ok(ServerFrame::Keystroke(Keystroke {
+                        message_id, edit, ..
+                    })) => {
+                        if !socket_echo.keystroke(message_id, &edit) {
+                            update_message_div(message_id, &edit);
+                        }
+                        update_msg_visibility();
+                    }
+                    Ok(ServerFrame::Ack { seq }) => socket_echo.acked(seq),
+                    Err(e) => console_log!("Invalid frame from server: {}", e),
+                }
             }
-            Err(e) => console_log!("Error receiving keystroke: {:?}", e),
-        }
-    });
-    ws_key.set_onmessage(Some(ws_key_onmessage.as_ref().unchecked_ref()));
-    ws_key_onmessage.forget();
-
-    let ws_key_onclose = Closure::<dyn FnMut(_)>::new(move |_e: web_sys::Event| {
-        //window().unwrap().location().reload().unwrap()
-    });
-    ws_key.set_onclose(Some(ws_key_onclose.as_ref().unchecked_ref()));
-    ws_key_onclose.forget();
+            Err(e) => console_log!("Error receiving frame: {:?}", e),
+        },
+    );
+    ws.connect()?;
 
-    // Add event listener to listen for keystrokes and broadcast them to the server
-    let ws_key_send = ws_key.clone();
-    let old_val: Arc<Mutex<String>> = Arc::new(Mutex::new(String::with_capacity(10)));
+    // Add event listener to listen for changes to the input and send them to the server as edits.
+    // Diffing the old and new value catches typing, deleting, pasting and replacing anywhere in the
+    // input, wherever the cursor is.
+    //
+    // Text being composed with an IME isn't final until the composition ends, so nothing is sent
+    // until then, and then the composed text is sent in one go.
+    let ws_send = ws.clone();
+    let keystroke_echo = echo.clone();
+    let composing = Rc::new(Cell::new(false));
     let old_val_ref = old_val.clone();
+    let keystroke_composing = composing.clone();
     let on_keystroke = Closure::<dyn FnMut(_)>::new(move |event: web_sys::Event| {
-        if let Ok(mut old_val) = old_val_ref.try_lock() {
-            let input: HtmlInputElement = event
-                .target()
-                .unwrap()
-                .dyn_into::<web_sys::HtmlInputElement>()
-                .unwrap();
-            let new_val: String = input.value();
-            if new_val == *old_val {
-                return;
-            }
-            let key;
-            if new_val.len() < old_val.len() {
-                key = '\x08';
-            } else {
-                key = new_val.chars().last().unwrap_or_default();
-            }
-            let mut key_bytes: [u8; 4] = [0; 4];
-            key.encode_utf8(&mut key_bytes);
-            if let Err(err) = ws_key_send.send_with_u8_array(&key_bytes) {
-                console_log!("Error sending key {}: {:?}", key, err);
-            }
-            *old_val = new_val;
-        } else {
-            console_log!("on_keystroke: couldn't access input value tracker string. Try again.");
+        if keystroke_composing.get() {
+            return;
         }
+        send_input_edits(&ws_send, &keystroke_echo, &old_val_ref, &event);
+    });
+    let composition_start_composing = composing.clone();
+    let on_composition_start = Closure::<dyn FnMut(_)>::new(move |_event: web_sys::Event| {
+        composition_start_composing.set(true);
+    });
+    let ws_send = ws.clone();
+    let composition_echo = echo.clone();
+    let old_val_ref = old_val.clone();
+    let on_composition_end = Closure::<dyn FnMut(_)>::new(move |event: web_sys::Event| {
+        composing.set(false);
+        send_input_edits(&ws_send, &composition_echo, &old_val_ref, &event);
     });
 
     let document = window()
         .and_then(|win| win.document())
         .expect("Could not access the document");
-    document
+    let message_input = document
         .get_element_by_id("message-input")
-        .expect("Message input does not exist")
+        .expect("Message input does not exist");
+    message_input
         .add_event_listener_with_callback("input", on_keystroke.as_ref().unchecked_ref())?;
+    message_input.add_event_listener_with_callback(
+        "compositionstart",
+        on_composition_start.as_ref().unchecked_ref(),
+    )?;
+    message_input.add_event_listener_with_callback(
+        "compositionend",
+        on_composition_end.as_ref().unchecked_ref(),
+    )?;
     on_keystroke.forget();
+    on_composition_start.forget();
+    on_composition_end.forget();
 
-    // Add event listener to reset the input value tracker and make a new message when Send is
-    // clicked
+    // Add event listener to make a new message when Send is clicked
     let sendbtn_current_message_ref = current_message.clone();
-    let old_val_ref = old_val.clone();
+    let sendbtn_echo = echo.clone();
     let on_sendbtn_click = Closure::<dyn FnMut(_)>::new(move |_event: web_sys::Event| {
+        // Forget the sent message right away, so its MessageEnd isn't mistaken for the server
+        // ending it early
+        let sent = sendbtn_current_message_ref
+            .lock()
+            .ok()
+            .and_then(|mut cur_msg| cur_msg.take());
         let sendbtn_current_message_ref = sendbtn_current_message_ref.clone();
-        let old_val_ref = old_val_ref.clone();
+        let echo = sendbtn_echo.clone();
         wasm_bindgen_futures::spawn_local(async move {
-            let new_msg: Message = new_msg().await.expect("Creating new message failed");
+            let new_msg: Message = match new_msg().await {
+                Ok(new_msg) => new_msg,
+                Err(err) => {
+                    console_log!("Creating new message failed: {:?}", err);
+                    // the server didn't finish the message either, so it is still being typed
+                    let cur_msg = sendbtn_current_message_ref.lock().ok();
+                    if let Some(mut cur_msg) = cur_msg.filter(|cur_msg| cur_msg.is_none()) {
+                        (*cur_msg) = sent;
+                    }
+                    window()
+                        .unwrap()
+                        .alert_with_message("Could not send your message, try again in a moment.")
+                        .ok();
+                    return;
+                }
+            };
+            // this empties the input, ready for the new message
+            echo.start(&new_msg);
             let mut cur_msg = sendbtn_current_message_ref
                 .lock()
                 .expect("Couldn't set new message");
             (*cur_msg) = Some(new_msg);
-            let input: HtmlInputElement = window()
-                .unwrap()
-                .document()
-                .unwrap()
-                .get_element_by_id("message-input")
-                .unwrap()
-                .dyn_into::<HtmlInputElement>()
-                .unwrap();
-            input.set_value("");
-            old_val_ref
-                .lock()
-                .expect("sendbtn_click: couldn't lock input value tracker string.")
-                .clear();
-            input.focus().ok();
+            let input = window()
+                .and_then(|win| win.document())
+                .and_then(|doc| doc.get_element_by_id("message-input"))
+                .and_then(|input| input.dyn_into::<HtmlInputElement>().ok());
+            if let Some(input) = input {
+                input.focus().ok();
+            }
         })
     });
     document
@@ -270,26 +297,47 @@ pub async fn run() -> Result<(), JsValue> {
         .add_event_listener_with_callback("click", on_sendbtn_click.as_ref().unchecked_ref())?;
     on_sendbtn_click.forget();
 
-    // Add event listener to prevent seeking in the text input, and to make the enter key trigger
-    // Send's click function
+    // Add event listener to the message container to play back messages when one of their replay
+    // buttons is clicked
+    let on_replay_click = Closure::<dyn FnMut(_)>::new(move |event: web_sys::Event| {
+        let Some(button) = event
+            .target()
+            .and_then(|target| target.dyn_into::<Element>().ok())
+            .and_then(|target| target.closest(".message-replay button").ok().flatten())
+        else {
+            return;
+        };
+        let message_id = button
+            .get_attribute("data-id")
+            .and_then(|id| id.parse::<u32>().ok());
+        let speed = button
+            .get_attribute("data-speed")
+            .and_then(|speed| speed.parse::<f64>().ok());
+        let (Some(message_id), Some(speed)) = (message_id, speed) else {
+            console_log!("Replay button is missing its data attributes");
+            return;
+        };
+        // a speed of 0 means instant
+        let speed = (speed > 0.0).then_some(speed);
+        spawn_local(async move {
+            if let Err(err) = replay_message(message_id, speed).await {
+                console_log!("Error replaying message {}: {:?}", message_id, err);
+            }
+        });
+    });
+    document
+        .get_element_by_id("messages-container")
+        .expect("Message container does not exist")
+        .add_event_listener_with_callback("click", on_replay_click.as_ref().unchecked_ref())?;
+    on_replay_click.forget();
+
+    // Add event listener to make the enter key trigger Send's click function
     let on_input_keydown =
         Closure::<dyn FnMut(web_sys::KeyboardEvent)>::new(move |event: web_sys::KeyboardEvent| {
             scroll_msg_cont_to_bottom();
-            const DISABLED_KEYS: &[&str] = &["ArrowLeft", "ArrowRight"];
-            if DISABLED_KEYS.contains(&event.key().as_str()) {
-                event.prevent_default();
-            }
             match event.key().as_str() {
-                "Enter" => {
-                    let submit_btn = window()
-                        .unwrap()
-                        .document()
-                        .unwrap()
-                        .get_element_by_id("send-button")
-                        .expect("Submit button does not exist");
-
-                    submit_btn.dyn_ref::<HtmlElement>().unwrap().click();
-                }
+                // Enter also confirms an IME composition, which shouldn't send the message
+                "Enter" if !event.is_composing() => click_send_button(),
                 _ => {
                     console_log!("Key: {}", event.key());
                 }
@@ -384,68 +432,813 @@ pub async fn run() -> Result<(), JsValue> {
     Ok(())
 }
 
-/// Update message div with a new char
-fn update_message_div(message_id: u32, key: char) {
+/// Send the edits that turn the last value of the input, `old_val`, into its value now, and echo
+/// them. They go in one frame, so a replaced selection arrives as a single change.
+fn send_input_edits(
+    ws: &ReconnectingSocket,
+    echo: &LocalEcho,
+    old_val: &Mutex<String>,
+    event: &web_sys::Event,
+) {
+    let Ok(mut old_val) = old_val.try_lock() else {
+        console_log!("on_keystroke: couldn't access input value tracker string. Try again.");
+        return;
+    };
+    let input: HtmlInputElement = event
+        .target()
+        .unwrap()
+        .dyn_into::<web_sys::HtmlInputElement>()
+        .unwrap();
+    let new_val: String = input.value();
+    if new_val == *old_val {
+        return;
+    }
+    let frame = echo.typed(Edit::diff(&old_val, &new_val));
+    if let Err(err) = ws.send(&frame.encode()) {
+        console_log!("Error sending {:?}: {:?}", frame, err);
+    }
+    *old_val = new_val;
+}
+
+/// Show an event from the room.
+///
+/// A MessageNew adds a new message div to the DOM, and MessageEnd marks the div finished. If the
+/// user's own message was ended by the server (e.g. they idled too long), it starts them a new one.
+fn handle_event(
+    event: Event,
+    current_message: &Mutex<Option<Message>>,
+    status: &ConnectionStatus,
+    echo: &LocalEcho,
+) {
+    update_msg_visibility();
+    match event {
+        Event::MessageNew(message) => {
+            // Create new div for the message
+            console_log!("Message id from server: {}", message.id);
+            insert_message_div(&message);
+            // the user may have typed into it before its div was here
+            echo.render();
+            scroll_msg_cont_to_bottom();
+        }
+        Event::MessageEnd { id } => {
+            finish_message_div(id);
+            let own_message = current_message
+                .lock()
+                .map(|cur_msg| cur_msg.as_ref().is_some_and(|msg| msg.id == id))
+                .unwrap_or(false);
+            if own_message {
+                click_send_button();
+            }
+        }
+        Event::MessageEvicted { id } => remove_message_div(id),
+        Event::Resync { messages } => {
+            resync_message_divs(&messages);
+            echo.resynced(&messages);
+        }
+        Event::UserJoined(author) => add_roster_user(&author),
+        Event::UserLeft(author) => remove_roster_user(&author),
+        Event::Online { count } => set_online_count(count),
+        // the socket closes next, and reconnects once the server is back
+        Event::ServerShuttingDown => status.server_shutting_down(),
+    }
+}
+
+/// The user's own message, echoed as they type instead of when the server sends it back.
+///
+/// Its div shows the message as the server has it, with the edits the server hasn't acked yet
+/// applied on top. The server sends the socket's own keystrokes back before acking them, so once a
+/// batch is acked, the server's text has whatever it made of it, and a resync or reconnect only
+/// has to take the server's copy.
+///
+/// The server doesn't always take everything: a full message drops what is typed past its end, and
+/// edits sent just before a reconnect can be lost. The next edits are worked out from the input, so
+/// once nothing is waiting, the input is put back in line with the server's copy.
+struct LocalEcho {
+    /// The id of the message being typed, and its text as the server has it
+    confirmed: RefCell<Option<(u32, String)>>,
+    /// Batches of edits sent to the server that it hasn't acked, oldest first
+    pending: RefCell<VecDeque<(u32, Vec<Edit>)>>,
+    next_seq: Cell<u32>,
+    /// The value of the input the next edits are worked out from
+    old_val: Arc<Mutex<String>>,
+}
+
+impl LocalEcho {
+    fn new(old_val: Arc<Mutex<String>>) -> Self {
+        LocalEcho {
+            confirmed: RefCell::new(None),
+            pending: RefCell::new(VecDeque::new()),
+            next_seq: Cell::new(0),
+            old_val,
+        }
+    }
+
+    /// Echo into a new message, forgetting the last one
+    fn start(&self, message: &Message) {
+        *self.confirmed.borrow_mut() = Some((message.id, message.text.clone()));
+        self.pending.borrow_mut().clear();
+        self.settle();
+    }
+
+    /// Number a batch of edits the user just typed, and show it
+    fn typed(&self, edits: Vec<Edit>) -> ClientFrame {
+        let seq = self.next_seq.get();
+        self.next_seq.set(seq.wrapping_add(1));
+        self.pending.borrow_mut().push_back((seq, edits.clone()));
+        self.render();
+        ClientFrame::Edits { seq, edits }
+    }
+
+    /// A keystroke from the server. Returns whether it was to the user's own message.
+    fn keystroke(&self, message_id: u32, edit: &Edit) -> bool {
+        match &mut *self.confirmed.borrow_mut() {
+            Some((id, text)) if *id == message_id => edit.apply(text),
+            _ => return false,
+        }
+        // while edits are waiting on the server this is most likely one of them coming back,
+        // which is already shown. Their ack shows the rest.
+        if self.pending.borrow().is_empty() {
+            self.settle();
+        }
+        true
+    }
+
+    /// The server is done with the batch `seq`, and every batch before it
+    fn acked(&self, seq: u32) {
+        let mut pending = self.pending.borrow_mut();
+        // an ack for a message that was already left behind
+        let Some(acked) = pending.iter().position(|(pending, _)| *pending == seq) else {
+            return;
+        };
+        pending.drain(..=acked);
+        let settled = pending.is_empty();
+        drop(pending);
+        if settled {
+            self.settle();
+        } else {
+            self.render();
+        }
+    }
+
+    /// The server's copy of these messages replaced the divs
+    fn resynced(&self, messages: &[Message]) {
+        let shown = self.shown();
+        let mut confirmed = self.confirmed.borrow_mut();
+        let Some((id, text)) = &mut *confirmed else {
+            return;
+        };
+        let Some(message) = messages.iter().find(|message| message.id == *id) else {
+            return;
+        };
+        text.clone_from(&message.text);
+        drop(confirmed);
+        if self.pending.borrow().is_empty() {
+            self.settle();
+        } else if let Some((id, shown)) = shown {
+            // the server's copy may already have some of the waiting edits, so keep showing what
+            // was shown until they are acked
+            set_message_text(id, &shown);
+        }
+    }
+
+    /// The socket reopened, so edits sent on the old one will never be acked. The server either
+    /// typed them or lost them, and the resync after reconnecting says which.
+    fn reconnected(&self) {
+        self.pending.borrow_mut().clear();
+    }
+
+    /// The message's id, and its text with the waiting edits applied
+    fn shown(&self) -> Option<(u32, String)> {
+        let (id, mut text) = self.confirmed.borrow().clone()?;
+        for (_, edits) in self.pending.borrow().iter() {
+            for edit in edits {
+                edit.apply(&mut text);
+            }
+        }
+        Some((id, text))
+    }
+
+    /// Show the message as it stands
+    fn render(&self) -> Option<String> {
+        let (id, text) = self.shown()?;
+        set_message_text(id, &text);
+        update_msg_visibility();
+        Some(text)
+    }
+
+    /// Show the message, and make the input match it if the server didn't take everything typed
+    /// into it. Only for when nothing is waiting on the server, so the message is its copy.
+    fn settle(&self) {
+        let Some(text) = self.render() else {
+            return;
+        };
+        // held while the input's edits are being sent, which are never waiting on this
+        let Ok(mut old_val) = self.old_val.try_lock() else {
+            return;
+        };
+        if *old_val == text {
+            return;
+        }
+        console_log!("The server didn't take all of the input, putting it back in line");
+        let input = window()
+            .and_then(|win| win.document())
+            .and_then(|doc| doc.get_element_by_id("message-input"))
+            .and_then(|input| input.dyn_into::<HtmlInputElement>().ok());
+        if let Some(input) = input {
+            input.set_value(&text);
+        }
+        *old_val = text;
+    }
+}
+
+/// The first reconnect waits this long, and every failed attempt after it doubles the wait
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How long to wait before reconnecting, after `failures` attempts in a row didn't connect
+fn reconnect_delay(failures: u32) -> Duration {
+    RECONNECT_MIN_DELAY
+        .saturating_mul(2u32.saturating_pow(failures))
+        .min(RECONNECT_MAX_DELAY)
+}
+
+/// Shows whether every websocket is connected, and keeps the input disabled until they are
+struct ConnectionStatus {
+    sockets: u32,
+    open: Cell<u32>,
+    connected_before: Cell<bool>,
+    /// The server said it was going away, and hasn't been reconnected to since
+    server_restarting: Cell<bool>,
+}
+
+impl ConnectionStatus {
+    fn new(sockets: u32) -> Self {
+        let status = ConnectionStatus {
+            sockets,
+            open: Cell::new(0),
+            connected_before: Cell::new(false),
+            server_restarting: Cell::new(false),
+        };
+        status.render();
+        status
+    }
+
+    fn opened(&self) {
+        self.open.set(self.open.get() + 1);
+        self.render();
+    }
+
+    fn closed(&self) {
+        self.open.set(self.open.get().saturating_sub(1));
+        self.render();
+    }
+
+    fn server_shutting_down(&self) {
+        self.server_restarting.set(true);
+        self.render();
+    }
+
+    fn render(&self) {
+        let connected = self.open.get() >= self.sockets;
+        let (state, text) = if connected {
+            self.connected_before.set(true);
+            self.server_restarting.set(false);
+            ("connected", "connected")
+        } else if self.server_restarting.get() {
+            ("restarting", "server restarting…")
+        } else if self.connected_before.get() {
+            ("reconnecting", "reconnecting…")
+        } else {
+            ("connecting", "connecting…")
+        };
+        let document = window()
+            .and_then(|win| win.document())
+            .expect("Could not access document");
+        if let Some(indicator) = document.get_element_by_id("connection-status") {
+            indicator.set_class_name(&format!("connection-status connection-{}", state));
+            indicator.set_text_content(Some(text));
+        }
+        // keys typed while disconnected would be lost, so don't let the user type them
+        if let Some(input) = document
+            .get_element_by_id("message-input")
+            .and_then(|input| input.dyn_into::<HtmlElement>().ok())
+        {
+            input
+                .toggle_attribute_with_force("disabled", !connected)
+                .ok();
+            if connected {
+                input.focus().ok();
+            }
+        }
+    }
+}
+
+/// A websocket that reopens itself with exponential backoff whenever it closes
+struct ReconnectingSocket {
+    name: &'static str,
+    url: String,
+    binary_type: Option<BinaryType>,
+    status: Rc<ConnectionStatus>,
+    /// The socket currently open or opening
+    socket: RefCell<Option<WebSocket>>,
+    /// Reconnect attempts in a row that didn't connect
+    failures: Cell<u32>,
+    opened_before: Cell<bool>,
+    /// Runs every time the socket opens, with whether it is a reconnect
+    on_open: Box<dyn Fn(bool)>,
+    on_message: Box<dyn Fn(MessageEvent)>,
+}
+
+impl ReconnectingSocket {
+    fn new(
+        name: &'static str,
+        url: String,
+        binary_type: Option<BinaryType>,
+        status: Rc<ConnectionStatus>,
+        on_open: impl Fn(bool) + 'static,
+        on_message: impl Fn(MessageEvent) + 'static,
+    ) -> Rc<Self> {
+        Rc::new(ReconnectingSocket {
+            name,
+            url,
+            binary_type,
+            status,
+            socket: RefCell::new(None),
+            failures: Cell::new(0),
+            opened_before: Cell::new(false),
+            on_open: Box::new(on_open),
+            on_message: Box::new(on_message),
+        })
+    }
+
+    /// Open a new socket. When it closes, another one is opened after a backoff.
+    fn connect(self: &Rc<Self>) -> Result<(), JsValue> {
+        let socket = WebSocket::new(&self.url)?;
+        if let Some(binary_type) = self.binary_type {
+            socket.set_binary_type(binary_type);
+        }
+        // whether this particular socket opened, so a failed attempt isn't counted as a close
+        let opened = Rc::new(Cell::new(false));
+
+        // the closures live as long as their socket, which can't be known here, so they are leaked
+        // like every other closure in this app. That's one set per reconnect.
+        let this = self.clone();
+        let this_opened = opened.clone();
+        let onopen = Closure::<dyn FnMut(_)>::new(move |_e: web_sys::Event| {
+            this_opened.set(true);
+            this.failures.set(0);
+            this.status.opened();
+            let reconnect = this.opened_before.replace(true);
+            (this.on_open)(reconnect);
+        });
+        socket.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+        onopen.forget();
+
+        let this = self.clone();
+        let onmessage = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| (this.on_message)(e));
+        socket.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        onmessage.forget();
+
+        let this = self.clone();
+        let onclose = Closure::<dyn FnMut(_)>::new(move |e: CloseEvent| {
+            console_log!(
+                "{} websocket closed ({}): {}",
+                this.name,
+                e.code(),
+                e.reason()
+            );
+            if opened.get() {
+                this.status.closed();
+            }
+            let delay = reconnect_delay(this.failures.get());
+            this.failures.set(this.failures.get().saturating_add(1));
+            let this = this.clone();
+            spawn_local(async move {
+                sleep(delay).await;
+                if let Err(err) = this.connect() {
+                    console_log!("Could not reconnect the {} websocket: {:?}", this.name, err);
+                }
+            });
+        });
+        socket.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+        onclose.forget();
+
+        *self.socket.borrow_mut() = Some(socket);
+        Ok(())
+    }
+
+    fn send(&self, bytes: &[u8]) -> Result<(), JsValue> {
+        match &*self.socket.borrow() {
+            Some(socket) => socket.send_with_u8_array(bytes),
+            None => Err(JsValue::from_str("Websocket is not connected")),
+        }
+    }
+}
+
+/// The websocket url of a route in the current room
+fn ws_url(path: &str) -> Result<String, JsValue> {
+    let location = window().unwrap().location();
+    let ws_protocol = if location.protocol()? == "https:" {
+        "wss:"
+    } else {
+        "ws:"
+    };
+    Ok(format!(
+        "{}//{}:{}{}",
+        ws_protocol,
+        location.hostname()?,
+        location.port()?,
+        room_api_url(path)
+    ))
+}
+
+/// Catch up on everything missed while disconnected. If the user's message was finished in the
+/// meantime, or they have none because making one failed while the server was away, they get a
+/// new one.
+async fn resume(
+    current_message: &Arc<Mutex<Option<Message>>>,
+    echo: &LocalEcho,
+) -> Result<(), JsValue> {
+    // everything from the oldest message that was still being typed, or else from the newest
+    // message, may have changed
+    let divs = message_div_ids();
+    let oldest_typing = divs
+        .iter()
+        .filter(|(_, typing)| *typing)
+        .map(|(id, _)| *id)
+        .min();
+    let newest = divs.iter().map(|(id, _)| *id).max();
+    let mut cursor = match (oldest_typing, newest) {
+        (Some(id), _) => id
+            .checked_sub(1)
+            .map_or(HistoryCursor::Latest, HistoryCursor::Since),
+        (None, Some(id)) => HistoryCursor::Since(id),
+        (None, None) => HistoryCursor::Latest,
+    };
+    let mut messages = Vec::new();
+    loop {
+        let page = get_msg(cursor).await?;
+        let full = page.len() >= HISTORY_PAGE;
+        let last = page.last().map(|msg| msg.id);
+        messages.extend(page);
+        match last {
+            Some(last) if full => cursor = HistoryCursor::Since(last),
+            _ => break,
+        }
+    }
+    resync_message_divs(&messages);
+    echo.resynced(&messages);
+    let own_finished = current_message
+        .lock()
+        .map(|cur_msg| {
+            cur_msg.as_ref().is_none_or(|own| {
+                messages
+                    .iter()
+                    .any(|message| message.id == own.id && message.finished)
+            })
+        })
+        .unwrap_or(false);
+    if own_finished {
+        click_send_button();
+    }
+    Ok(())
+}
+
+/// Apply an edit to a message div
+fn update_message_div(message_id: u32, edit: &Edit) {
     let document = window()
         .and_then(|win| win.document())
         .expect("Could not access document");
 
-    let ui_message_ele = document
-        .get_element_by_id(&format!("message-body-{}", message_id.to_string()))
-        .expect(&format!(
-            "Could not get #message-body-{} to update it",
-            message_id
-        ));
-    let mut text = ui_message_ele.inner_html();
-    if key == '\x08' {
-        text.pop().unwrap();
-    } else {
-        text.push(key);
+    // the message may be too old to have been loaded
+    let Some(ui_message_ele) = document.get_element_by_id(&format!("message-body-{}", message_id))
+    else {
+        return;
+    };
+    let mut text = ui_message_ele.text_content().unwrap_or_default();
+    edit.apply(&mut text);
+    ui_message_ele.set_text_content(Some(&text));
+}
+
+/// Replace the text of a message div, if it is loaded
+fn set_message_text(message_id: u32, text: &str) {
+    let ui_message_ele = window()
+        .and_then(|win| win.document())
+        .and_then(|doc| doc.get_element_by_id(&format!("message-body-{}", message_id)));
+    if let Some(ui_message_ele) = ui_message_ele {
+        ui_message_ele.set_text_content(Some(text));
     }
-    ui_message_ele.set_inner_html(&text);
 }
 
-/// Add a new message div to the DOM.
-fn insert_message_div(message_id: u32, text: &str) -> Element {
-    const MESSAGE_TMPL: &str = r#"<div class="message-sender">&lt;Anon&gt;</div><div class="message-body" id="message-body-{id}">{body}</div>"#;
+/// Re-type a message into its div at `speed` times the pace it was originally typed.
+///
+/// A speed of `None` replays instantly. Starting another replay of the same message cancels this
+/// one. Messages still being typed can't be replayed until they are finished.
+async fn replay_message(message_id: u32, speed: Option<f64>) -> Result<(), JsValue> {
+    static NEXT_REPLAY: AtomicU32 = AtomicU32::new(0);
+
+    let keystrokes = get_replay(message_id).await?;
+    if keystrokes.is_empty() {
+        // nothing was typed live (e.g. the welcome message), so there is nothing to play back
+        return Ok(());
+    }
 
     let document = window()
         .and_then(|win| win.document())
-        .expect("Could not access the document");
+        .ok_or_else(|| JsValue::from_str("Could not access the document"))?;
+    let typing = document
+        .get_element_by_id(&format!("message-{}", message_id))
+        .is_some_and(|div| div.class_list().contains("message-typing"));
+    if typing {
+        // its live keystrokes would land in the middle of the replay
+        return Err(JsValue::from_str("Message is still being typed"));
+    }
+    let ui_message_ele = document
+        .get_element_by_id(&format!("message-body-{}", message_id))
+        .ok_or_else(|| JsValue::from_str("Message div does not exist"))?;
+    let replay_id = NEXT_REPLAY.fetch_add(1, Ordering::Relaxed).to_string();
+    ui_message_ele.set_attribute("data-replay", &replay_id)?;
+    ui_message_ele.set_text_content(None);
 
-    let mut text_no_bksp = String::with_capacity(text.len());
-    for c in text.chars() {
-        if c == '\x08' {
-            text_no_bksp.pop();
-        } else {
-            text_no_bksp.push(c);
+    let mut elapsed = Duration::ZERO;
+    for keystroke in keystrokes {
+        if let Some(speed) = speed {
+            sleep(keystroke.time.saturating_sub(elapsed).div_f64(speed)).await;
+            elapsed = keystroke.time;
+            if ui_message_ele.get_attribute("data-replay") != Some(replay_id.clone()) {
+                return Ok(());
+            }
         }
+        update_message_div(message_id, &keystroke.edit);
     }
+    ui_message_ele.remove_attribute("data-replay")
+}
+
+/// Wait without blocking the page
+async fn sleep(duration: Duration) {
+    let millis = i32::try_from(duration.as_millis()).unwrap_or(i32::MAX);
+    let promise = Promise::new(&mut |resolve, _reject| {
+        window()
+            .unwrap()
+            .set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, millis)
+            .ok();
+    });
+    JsFuture::from(promise).await.ok();
+}
+
+/// Mark a message div as finished, so it stops looking like someone is typing it and can be
+/// replayed
+fn finish_message_div(message_id: u32) {
+    let document = window()
+        .and_then(|win| win.document())
+        .expect("Could not access document");
+    if let Some(ui_message_ele) = document.get_element_by_id(&format!("message-{}", message_id)) {
+        ui_message_ele.class_list().remove_1("message-typing").ok();
+        // the replay buttons are the only buttons in it
+        let buttons = ui_message_ele.get_elements_by_tag_name("button");
+        for index in 0..buttons.length() {
+            if let Some(button) = buttons.item(index) {
+                button.remove_attribute("disabled").ok();
+            }
+        }
+    }
+}
+
+/// Press Send, which finishes the user's message and starts a new one
+fn click_send_button() {
+    let submit_btn = window()
+        .unwrap()
+        .document()
+        .unwrap()
+        .get_element_by_id("send-button")
+        .expect("Submit button does not exist");
+
+    submit_btn.dyn_ref::<HtmlElement>().unwrap().click();
+}
+
+/// Who messages without an author are from
+const SENDER_LABEL: &str = "<Anon>";
+
+/// The inline style that colors an author's name, the same on every client
+fn author_style(author: &Author) -> String {
+    format!("color: hsl({}, 65%, 45%)", author.hue())
+}
+
+/// Add a new message div to the DOM.
+///
+/// Messages that are still being typed get the `message-typing` class until they are finished.
+/// The sender is colored by author, the same on every client.
+fn insert_message_div(message: &Message) -> Element {
+    let message_id = message.id;
+    let document = window()
+        .and_then(|win| win.document())
+        .expect("Could not access the document");
 
     let ui_messages_cont = document
         .get_element_by_id("messages-container")
         .expect("Message container does not exist");
-    let ui_message_str = MESSAGE_TMPL
-        .replace("{id}", &message_id.to_string())
-        .replace("{body}", &text_no_bksp);
     let ui_message_ele = document.create_element("div").unwrap();
     ui_message_ele.set_id(&format!("message-{}", &message_id.to_string()));
     ui_message_ele.set_class_name("message message-invisible");
-    ui_message_ele.set_inner_html(&ui_message_str);
+    if !message.finished {
+        ui_message_ele.class_list().add_1("message-typing").ok();
+    }
+
+    // Anything a user typed only ever goes into text nodes, never through HTML parsing
+    let ui_sender = document.create_element("div").unwrap();
+    ui_sender.set_class_name("message-sender");
+    match &message.author {
+        Some(author) => {
+            ui_sender.set_text_content(Some(author.display_name()));
+            ui_sender
+                .set_attribute("style", &author_style(author))
+                .unwrap();
+        }
+        None => ui_sender.set_text_content(Some(SENDER_LABEL)),
+    }
+
+    let ui_body = document.create_element("div").unwrap();
+    ui_body.set_class_name("message-body");
+    ui_body.set_id(&format!("message-body-{}", message_id));
+    ui_body
+        .append_child(&document.create_text_node(&message.text))
+        .unwrap();
+
+    let ui_replay = document.create_element("div").unwrap();
+    ui_replay.set_class_name("message-replay");
+    for (speed, label) in [("1", "1x"), ("2", "2x"), ("0", "instant")] {
+        let button = document.create_element("button").unwrap();
+        button
+            .set_attribute("data-id", &message_id.to_string())
+            .unwrap();
+        button.set_attribute("data-speed", speed).unwrap();
+        // replaying a message while it is typed would mix the replay into the live keystrokes
+        if !message.finished {
+            button.set_attribute("disabled", "").unwrap();
+        }
+        button.set_text_content(Some(label));
+        ui_replay.append_child(&button).unwrap();
+    }
+
+    for child in [&ui_sender, &ui_body, &ui_replay] {
+        ui_message_ele.append_child(child).unwrap();
+    }
     ui_messages_cont
         .append_child(&ui_message_ele)
         .expect("Unable to append msg to DOM");
     ui_message_ele
 }
 
-/// hit the /msg/get endpoint
-async fn get_msg() -> Result<Vec<Message>, JsValue> {
+/// Remove a message the server no longer has
+fn remove_message_div(message_id: u32) {
+    if let Some(ui_message_ele) = window()
+        .and_then(|win| win.document())
+        .and_then(|doc| doc.get_element_by_id(&format!("message-{}", message_id)))
+    {
+        ui_message_ele.remove();
+    }
+}
+
+/// Replace the divs of messages the client fell behind on with the server's copy, adding any that
+/// were missed entirely
+fn resync_message_divs(messages: &[Message]) {
+    let document = window()
+        .and_then(|win| win.document())
+        .expect("Could not access document");
+    for message in messages {
+        match document.get_element_by_id(&format!("message-body-{}", message.id)) {
+            Some(ui_message_body) => {
+                // the server's copy wins over any replay in progress
+                ui_message_body.remove_attribute("data-replay").ok();
+                ui_message_body.set_text_content(Some(&message.text));
+                if message.finished {
+                    finish_message_div(message.id);
+                }
+            }
+            None => {
+                insert_message_div(message);
+            }
+        }
+    }
+    update_msg_visibility();
+    scroll_msg_cont_to_bottom();
+}
+
+/// How many messages to ask for per page of history
+const HISTORY_PAGE: usize = 50;
+
+/// Which page of history to ask `/msg/get` for
+#[derive(Clone, Copy)]
+enum HistoryCursor {
+    /// The newest messages
+    Latest,
+    /// The messages just older than this id
+    Before(u32),
+    /// The messages just newer than this id
+    Since(u32),
+}
+
+/// How much of the room's history is in the message container
+struct History {
+    /// The oldest message loaded
+    oldest: Cell<Option<u32>>,
+    /// Whether the server has messages older than `oldest`
+    more: Cell<bool>,
+    loading: Cell<bool>,
+}
+
+/// Load older pages of history into the top of the message container, until it has enough to
+/// scroll or there are none left
+async fn load_older_messages(history: Rc<History>) -> Result<(), JsValue> {
+    if history.loading.replace(true) {
+        return Ok(());
+    }
+    let result = async {
+        while history.more.get() {
+            let Some(oldest) = history.oldest.get() else {
+                break;
+            };
+            let page = get_msg(HistoryCursor::Before(oldest)).await?;
+            history.more.set(page.len() >= HISTORY_PAGE);
+            if let Some(first) = page.first() {
+                history.oldest.set(Some(first.id));
+            }
+            prepend_message_divs(&page);
+            if msg_cont_scrollable() {
+                break;
+            }
+        }
+        Ok(())
+    }
+    .await;
+    history.loading.set(false);
+    result
+}
+
+/// Put older messages at the top of the message container, without moving what the user sees
+fn prepend_message_divs(messages: &[Message]) {
+    let msg_cont = window()
+        .and_then(|win| win.document())
+        .and_then(|doc| doc.get_element_by_id("messages-container"))
+        .and_then(|msg_cont| msg_cont.dyn_into::<HtmlElement>().ok())
+        .expect("Message container does not exist");
+    let old_height = msg_cont.scroll_height();
+    for message in messages.iter().rev() {
+        let ui_message_ele = insert_message_div(message);
+        msg_cont
+            .insert_before(&ui_message_ele, msg_cont.first_child().as_ref())
+            .ok();
+    }
+    update_msg_visibility();
+    // jump, rather than smooth scroll, past the new messages
+    let style = msg_cont.style();
+    style.set_property("scroll-behavior", "auto").ok();
+    msg_cont.set_scroll_top(msg_cont.scroll_top() + msg_cont.scroll_height() - old_height);
+    style.remove_property("scroll-behavior").ok();
+}
+
+/// Whether the message container has more messages than fit in it
+fn msg_cont_scrollable() -> bool {
+    window()
+        .and_then(|win| win.document())
+        .and_then(|doc| doc.get_element_by_id("messages-container"))
+        .is_some_and(|msg_cont| msg_cont.scroll_height() > msg_cont.client_height())
+}
+
+/// The id of every message div, and whether it is still being typed
+fn message_div_ids() -> Vec<(u32, bool)> {
+    let msg_cont = window()
+        .and_then(|win| win.document())
+        .and_then(|doc| doc.get_element_by_id("messages-container"))
+        .expect("Message container does not exist");
+    let children = msg_cont.children();
+    (0..children.length())
+        .filter_map(|i| children.item(i))
+        .filter_map(|msg| {
+            let id = msg.id().strip_prefix("message-")?.parse::<u32>().ok()?;
+            Some((id, msg.class_list().contains("message-typing")))
+        })
+        .collect()
+}
+
+/// hit the /msg/get endpoint for a page of history
+async fn get_msg(cursor: HistoryCursor) -> Result<Vec<Message>, JsValue> {
     // TODO: must handle request failed / server down. Currently results in JSON parse fail.
     let r_opts = RequestInit::new();
     r_opts.set_method("GET");
     r_opts.set_mode(RequestMode::SameOrigin);
     r_opts.set_credentials(RequestCredentials::Include);
-    let msg_get_url = String::from("/api/msg/get");
+    let cursor = match cursor {
+        HistoryCursor::Latest => String::new(),
+        HistoryCursor::Before(id) => format!("&before={}", id),
+        HistoryCursor::Since(id) => format!("&since={}", id),
+    };
+    let msg_get_url = format!(
+        "{}?limit={}{}",
+        room_api_url("/msg/get"),
+        HISTORY_PAGE,
+        cursor
+    );
     let r = Request::new_with_str_and_init(&msg_get_url, &r_opts)?;
     let window = window().unwrap();
     let resp_val = JsFuture::from(window.fetch_with_request(&r)).await?;
@@ -460,14 +1253,61 @@ async fn get_msg() -> Result<Vec<Message>, JsValue> {
         .map_err(|err| JsValue::from_str(&err.to_string()))
 }
 
+/// Hit the /msg/{id}/replay endpoint
+async fn get_replay(message_id: u32) -> Result<Vec<Keystroke>, JsValue> {
+    let r_opts = RequestInit::new();
+    r_opts.set_method("GET");
+    r_opts.set_mode(RequestMode::SameOrigin);
+    r_opts.set_credentials(RequestCredentials::Include);
+    let msg_replay_url = room_api_url(&format!("/msg/{}/replay", message_id));
+    let r = Request::new_with_str_and_init(&msg_replay_url, &r_opts)?;
+    let window = window().unwrap();
+    let resp_val = JsFuture::from(window.fetch_with_request(&r)).await?;
+    let resp: Response = resp_val.dyn_into().unwrap();
+    if !resp.ok() {
+        return Err(JsValue::from_str(&format!(
+            "Replay request failed: {}",
+            resp.status()
+        )));
+    }
+
+    let resp_json = JsFuture::from(resp.text()?).await?;
+    let resp_json_str = resp_json.as_string();
+    if resp_json_str.is_none() {
+        return Err(JsValue::from_str("JSON to_string() returned None"));
+    }
+    serde_json::from_str::<Vec<Keystroke>>(&resp_json_str.unwrap())
+        .map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+/// Hit the /msg/new endpoint until it makes a message, waiting longer after every failure. The
+/// server turns the request down while the user is making messages too fast, or shutting down.
+async fn new_msg_retrying() -> Message {
+    let mut failures = 0;
+    loop {
+        match new_msg().await {
+            Ok(new_msg) => return new_msg,
+            Err(err) => {
+                let delay = reconnect_delay(failures);
+                console_log!(
+                    "Creating new message failed, retrying in {:?}: {:?}",
+                    delay,
+                    err
+                );
+                failures = failures.saturating_add(1);
+                sleep(delay).await;
+            }
+        }
+    }
+}
+
 /// Hit the /msg/new endpoint
 async fn new_msg() -> Result<Message, JsValue> {
-    // TODO: must handle request failed / server down. Currently results in JSON parse fail.
     let r_opts = RequestInit::new();
     r_opts.set_method("GET");
     r_opts.set_mode(RequestMode::SameOrigin);
     r_opts.set_credentials(RequestCredentials::Include);
-    let msg_get_url = String::from("/api/msg/new");
+    let msg_get_url = room_api_url("/msg/new");
     let r = Request::new_with_str_and_init(&msg_get_url, &r_opts)?;
     let window = window().unwrap();
     let resp_val = JsFuture::from(window.fetch_with_request(&r)).await?;
@@ -478,10 +1318,83 @@ async fn new_msg() -> Result<Message, JsValue> {
     if resp_json_str.is_none() {
         return Err(JsValue::from_str("JSON to_string() returned None"));
     }
+    // rate limited and shutting down servers answer with a JSON string saying why
+    if !resp.ok() {
+        return Err(JsValue::from_str(&format!(
+            "{}: {}",
+            resp.status(),
+            resp_json_str.unwrap()
+        )));
+    }
     serde_json::from_str::<Message>(&resp_json_str.unwrap())
         .map_err(|err| JsValue::from_str(&err.to_string()))
 }
 
+/// Hit the /rooms endpoint to list rooms
+async fn get_rooms() -> Result<Vec<RoomInfo>, JsValue> {
+    let r_opts = RequestInit::new();
+    r_opts.set_method("GET");
+    r_opts.set_mode(RequestMode::SameOrigin);
+    r_opts.set_credentials(RequestCredentials::Include);
+    let r = Request::new_with_str_and_init("/api/rooms", &r_opts)?;
+    let window = window().unwrap();
+    let resp_val = JsFuture::from(window.fetch_with_request(&r)).await?;
+    let resp: Response = resp_val.dyn_into().unwrap();
+
+    let resp_json = JsFuture::from(resp.text()?).await?;
+    let resp_json_str = resp_json.as_string();
+    if resp_json_str.is_none() {
+        return Err(JsValue::from_str("JSON to_string() returned None"));
+    }
+    serde_json::from_str::<Vec<RoomInfo>>(&resp_json_str.unwrap())
+        .map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+/// Hit the /presence endpoint to get who is in the room
+async fn get_presence() -> Result<Presence, JsValue> {
+    let r_opts = RequestInit::new();
+    r_opts.set_method("GET");
+    r_opts.set_mode(RequestMode::SameOrigin);
+    r_opts.set_credentials(RequestCredentials::Include);
+    let r = Request::new_with_str_and_init(&room_api_url("/presence"), &r_opts)?;
+    let window = window().unwrap();
+    let resp_val = JsFuture::from(window.fetch_with_request(&r)).await?;
+    let resp: Response = resp_val.dyn_into().unwrap();
+
+    let resp_json = JsFuture::from(resp.text()?).await?;
+    let Some(resp_json_str) = resp_json.as_string() else {
+        return Err(JsValue::from_str("JSON to_string() returned None"));
+    };
+    serde_json::from_str::<Presence>(&resp_json_str)
+        .map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+/// Hit the /rooms endpoint to create a room, or join it if it already exists
+async fn new_room(name: &str) -> Result<RoomInfo, JsValue> {
+    let body = serde_json::to_string(&RoomInfo {
+        name: name.to_string(),
+    })
+    .map_err(|err| JsValue::from_str(&err.to_string()))?;
+    let r_opts = RequestInit::new();
+    r_opts.set_method("POST");
+    r_opts.set_mode(RequestMode::SameOrigin);
+    r_opts.set_credentials(RequestCredentials::Include);
+    r_opts.set_body(&JsValue::from_str(&body));
+    let r = Request::new_with_str_and_init("/api/rooms", &r_opts)?;
+    r.headers().set("Content-Type", "application/json")?;
+    let window = window().unwrap();
+    let resp_val = JsFuture::from(window.fetch_with_request(&r)).await?;
+    let resp: Response = resp_val.dyn_into().unwrap();
+
+    let resp_json = JsFuture::from(resp.text()?).await?;
+    let resp_json_str = resp_json.as_string().unwrap_or_default();
+    if !resp.ok() {
+        return Err(JsValue::from_str(&resp_json_str));
+    }
+    serde_json::from_str::<RoomInfo>(&resp_json_str)
+        .map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
 /// Reset the session variable when connecting to the server
 async fn new_session() -> Result<(), JsValue> {
     // TODO: must handle request failed / server down. Currently results in JSON parse fail.
@@ -499,6 +1412,135 @@ async fn new_session() -> Result<(), JsValue> {
     Ok(())
 }
 
+/// The room named by the URL path, or `None` for the default room at `/`
+fn current_room() -> Option<String> {
+    let path = window()?.location().pathname().ok()?;
+    let name = path.trim_matches('/');
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Build the URL of a room scoped API route, e.g. `room_api_url("/msg/get")`
+fn room_api_url(path: &str) -> String {
+    match current_room() {
+        Some(room) => format!("/api/rooms/{}{}", room, path),
+        None => format!("/api{}", path),
+    }
+}
+
+/// Replace the roster and online count with `presence`
+fn render_presence(presence: &Presence) {
+    let Some(roster) = presence_roster() else {
+        return;
+    };
+    roster.set_text_content(None);
+    for author in &presence.users {
+        add_roster_user(author);
+    }
+    set_online_count(u32::try_from(presence.users.len()).unwrap_or(u32::MAX));
+}
+
+fn presence_roster() -> Option<Element> {
+    window()?.document()?.get_element_by_id("presence-roster")
+}
+
+/// The roster entry of the author with this pseudonym
+fn roster_item(roster: &Element, pseudonym: &str) -> Option<Element> {
+    let items = roster.children();
+    (0..items.length())
+        .filter_map(|index| items.item(index))
+        .find(|item| item.get_attribute("data-pseudonym").as_deref() == Some(pseudonym))
+}
+
+/// Add an author to the roster, in their color, unless they are on it already
+fn add_roster_user(author: &Author) {
+    let Some(roster) = presence_roster() else {
+        return;
+    };
+    if roster_item(&roster, &author.pseudonym).is_some() {
+        return;
+    }
+    let document = window()
+        .and_then(|win| win.document())
+        .expect("Could not access the document");
+    let item = document.create_element("li").unwrap();
+    item.set_attribute("data-pseudonym", &author.pseudonym)
+        .unwrap();
+    item.set_attribute("style", &author_style(author)).unwrap();
+    item.set_text_content(Some(author.display_name()));
+    roster.append_child(&item).ok();
+}
+
+fn remove_roster_user(author: &Author) {
+    if let Some(item) = presence_roster().and_then(|roster| roster_item(&roster, &author.pseudonym))
+    {
+        item.remove();
+    }
+}
+
+fn set_online_count(count: u32) {
+    if let Some(ui_count) = window()
+        .and_then(|win| win.document())
+        .and_then(|doc| doc.get_element_by_id("presence-count"))
+    {
+        ui_count.set_text_content(Some(&format!("{count} online")));
+    }
+}
+
+/// Fill #room-switcher with a link to every room, plus a link to make a new one.
+///
+/// Switching rooms is just navigating to the room's path, which reloads the app in that room.
+fn render_room_switcher(rooms: &[RoomInfo], current: Option<&str>) -> Result<(), JsValue> {
+    let document = window()
+        .and_then(|win| win.document())
+        .expect("Could not access the document");
+    let switcher = document
+        .get_element_by_id("room-switcher")
+        .expect("#room-switcher does not exist");
+    let current = current.unwrap_or(cavalier_protocol::DEFAULT_ROOM);
+
+    for room in rooms {
+        let link = document.create_element("a")?;
+        // the default room lives at /
+        let href = if room.name == cavalier_protocol::DEFAULT_ROOM {
+            String::from("/")
+        } else {
+            format!("/{}", room.name)
+        };
+        link.set_attribute("href", &href)?;
+        link.set_class_name("room-link");
+        if room.name == current {
+            link.class_list().add_1("room-current")?;
+        }
+        link.set_text_content(Some(&room.name));
+        switcher.append_child(&link)?;
+    }
+
+    let new_room_link = document.create_element("a")?;
+    new_room_link.set_attribute("href", "#")?;
+    new_room_link.set_class_name("room-link room-new");
+    new_room_link.set_text_content(Some("+ new room"));
+    let on_new_room_click = Closure::<dyn FnMut(_)>::new(move |event: web_sys::Event| {
+        event.prevent_default();
+        let window = window().unwrap();
+        let Ok(Some(name)) = window.prompt_with_message("Room name (a-z, 0-9, - and _):") else {
+            return;
+        };
+        let name = name.trim().to_lowercase();
+        if valid_room_name(&name) {
+            window.location().set_href(&format!("/{}", name)).ok();
+        } else {
+            window
+                .alert_with_message("Room names may only contain a-z, 0-9, - and _")
+                .ok();
+        }
+    });
+    new_room_link
+        .add_event_listener_with_callback("click", on_new_room_click.as_ref().unchecked_ref())?;
+    on_new_room_click.forget();
+    switcher.append_child(&new_room_link)?;
+    Ok(())
+}
+
 /// Scroll the message container to the bottom.
 ///
 /// This makes the chat experience much less annoying.
@@ -538,7 +1580,13 @@ pub fn update_msg_visibility() {
             .query_selector(".message-body")
             .expect("msg has no body!")
             .unwrap();
-        let empty: bool = msg_body.inner_html().trim().is_empty();
+        // messages being replayed start out empty, but should stay put
+        let empty: bool = msg_body
+            .text_content()
+            .unwrap_or_default()
+            .trim()
+            .is_empty()
+            && !msg_body.has_attribute("data-replay");
         let classes = msg.class_list();
         if empty {
             classes.add_1("message-invisible").ok();
@@ -548,3 +1596,148 @@ pub fn update_msg_visibility() {
         // msg.set_class_na
     }
 }
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::*;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    const HTML: &str = "<img src=x onerror=\"document.title='pwned'\"><b>bold</b>";
+
+    /// Give the test page the container the app renders messages into
+    fn messages_container() -> Element {
+        let document = window().unwrap().document().unwrap();
+        if let Some(msg_cont) = document.get_element_by_id("messages-container") {
+            return msg_cont;
+        }
+        let msg_cont = document.create_element("div").unwrap();
+        msg_cont.set_id("messages-container");
+        document.body().unwrap().append_child(&msg_cont).unwrap();
+        msg_cont
+    }
+
+    fn message_body(message_id: u32) -> Element {
+        window()
+            .unwrap()
+            .document()
+            .unwrap()
+            .get_element_by_id(&format!("message-body-{}", message_id))
+            .unwrap()
+    }
+
+    fn message(id: u32, text: &str, finished: bool) -> Message {
+        Message {
+            id,
+            text: String::from(text),
+            finished,
+            author: None,
+        }
+    }
+
+    fn sender(ui_message_ele: &Element) -> Element {
+        ui_message_ele
+            .query_selector(".message-sender")
+            .unwrap()
+            .unwrap()
+    }
+
+    #[wasm_bindgen_test]
+    fn inserted_html_is_text() {
+        messages_container();
+        let ui_message_ele = insert_message_div(&message(1000, HTML, true));
+        let body = message_body(1000);
+        assert_eq!(body.text_content().unwrap(), HTML);
+        assert_eq!(body.child_element_count(), 0);
+        assert!(ui_message_ele.query_selector("img").unwrap().is_none());
+        let sender = sender(&ui_message_ele);
+        assert_eq!(sender.text_content().unwrap(), SENDER_LABEL);
+        assert_eq!(sender.child_element_count(), 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn nickname_html_is_text() {
+        messages_container();
+        let author = Author {
+            pseudonym: String::from("Quiet Heron 3"),
+            nickname: Some(String::from(HTML)),
+        };
+        let ui_message_ele = insert_message_div(&Message {
+            author: Some(author.clone()),
+            ..message(1003, "hi", true)
+        });
+        let sender = sender(&ui_message_ele);
+        assert_eq!(sender.text_content().unwrap(), HTML);
+        assert_eq!(sender.child_element_count(), 0);
+        assert_eq!(
+            sender.get_attribute("style").unwrap(),
+            author_style(&author)
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn typed_html_is_text() {
+        messages_container();
+        insert_message_div(&message(1001, "", false));
+        for (at, key) in HTML.chars().enumerate() {
+            let edit = Edit::Insert {
+                at: at as u32,
+                text: key.to_string(),
+            };
+            update_message_div(1001, &edit);
+        }
+        let body = message_body(1001);
+        assert_eq!(body.text_content().unwrap(), HTML);
+        assert_eq!(body.child_element_count(), 0);
+
+        update_message_div(1001, &Edit::Delete { at: 0, len: 1 });
+        assert_eq!(body.text_content().unwrap(), &HTML[1..]);
+    }
+
+    #[wasm_bindgen_test]
+    fn resynced_html_is_text() {
+        messages_container();
+        insert_message_div(&message(1002, "safe", false));
+        resync_message_divs(&[message(1002, HTML, true)]);
+        let body = message_body(1002);
+        assert_eq!(body.text_content().unwrap(), HTML);
+        assert_eq!(body.child_element_count(), 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn echoed_edits_show_once() {
+        messages_container();
+        let own = message(1004, "", false);
+        insert_message_div(&own);
+        let old_val = Arc::new(Mutex::new(String::new()));
+        let echo = LocalEcho::new(old_val.clone());
+        echo.start(&own);
+        let insert = |at: u32, text: &str| Edit::Insert {
+            at,
+            text: String::from(text),
+        };
+        let body = message_body(1004);
+
+        // shown before the server has it
+        let ClientFrame::Edits { seq: first, .. } = echo.typed(vec![insert(0, "hi")]);
+        echo.typed(vec![insert(2, "!")]);
+        assert_eq!(body.text_content().unwrap(), "hi!");
+
+        // the server sends the first batch back and acks it, while the second is still out
+        assert!(echo.keystroke(1004, &insert(0, "hi")));
+        assert_eq!(body.text_content().unwrap(), "hi!");
+        echo.acked(first);
+        assert_eq!(body.text_content().unwrap(), "hi!");
+
+        // the server dropped the second batch: the ack takes its copy, and the next edits are
+        // worked out from it
+        *old_val.lock().unwrap() = String::from("hi!");
+        echo.acked(first.wrapping_add(1));
+        assert_eq!(body.text_content().unwrap(), "hi");
+        assert_eq!(*old_val.lock().unwrap(), "hi");
+
+        // someone else's keystrokes are left to the caller
+        assert!(!echo.keystroke(1, &insert(0, "x")));
+    }
+}
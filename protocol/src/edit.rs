@@ -0,0 +1,312 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Edits to the text of a message
+//!
+//! The author's client diffs its input before and after every change into `Edit`s, and the
+//! server and every client apply them the same way with `Edit::apply`. Offsets and lengths count
+//! `char`s (unicode scalar values), not bytes or UTF-16 code units.
+//!
+//! Deleting works on grapheme clusters, the characters a reader sees: a family emoji, a flag or
+//! an `e` followed by a combining accent is deleted whole, never left half there.
+
+use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// One change to the text of a message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "op")]
+pub enum Edit {
+    /// Insert `text` before the char at `at`
+    Insert { at: u32, text: String },
+    /// Delete `len` chars starting at `at`, widened to the grapheme clusters they touch
+    Delete { at: u32, len: u32 },
+}
+
+impl Edit {
+    /// Apply the edit to `text`.
+    ///
+    /// Offsets past the end of the text are clamped to the end, so an out of date edit changes
+    /// the text the same way everywhere instead of failing in some places. A delete that would
+    /// split a grapheme cluster deletes the whole cluster instead.
+    pub fn apply(&self, text: &mut String) {
+        match self {
+            Edit::Insert { at, text: inserted } => {
+                let at = byte_offset(text, *at);
+                text.insert_str(at, inserted);
+            }
+            Edit::Delete { at, len } => {
+                let start = byte_offset(text, *at);
+                let end = byte_offset(text, at.saturating_add(*len));
+                if start < end {
+                    let (start, end) = grapheme_bounds(text, start, end);
+                    text.replace_range(start..end, "");
+                }
+            }
+        }
+    }
+
+    /// The edits that turn `old` into `new`: deleting whatever changed between their common
+    /// prefix and suffix, then inserting its replacement.
+    ///
+    /// The prefix and suffix are made of whole grapheme clusters, so the edits never split one.
+    pub fn diff(old: &str, new: &str) -> Vec<Edit> {
+        let old: Vec<&str> = old.graphemes(true).collect();
+        let new: Vec<&str> = new.graphemes(true).collect();
+        let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let char_count = |graphemes: &[&str]| -> u32 {
+            let chars: usize = graphemes.iter().map(|g| g.chars().count()).sum();
+            u32::try_from(chars).unwrap_or(u32::MAX)
+        };
+        let deleted = char_count(&old[prefix..old.len() - suffix]);
+        let inserted: String = new[prefix..new.len() - suffix].concat();
+
+        let at = char_count(&old[..prefix]);
+        let mut edits = Vec::with_capacity(2);
+        if deleted > 0 {
+            edits.push(Edit::Delete { at, len: deleted });
+        }
+        if !inserted.is_empty() {
+            edits.push(Edit::Insert { at, text: inserted });
+        }
+        edits
+    }
+
+    /// The edit, cut down so that applying it to `text` leaves at most `max_chars` chars.
+    ///
+    /// Only inserts can grow the text. They lose whatever doesn't fit, in whole grapheme
+    /// clusters, and become `None` if nothing fits.
+    pub fn fit(self, text: &str, max_chars: usize) -> Option<Edit> {
+        let Edit::Insert { at, text: inserted } = self else {
+            return Some(self);
+        };
+        let mut room = max_chars.saturating_sub(text.chars().count());
+        let fitted: String = inserted
+            .graphemes(true)
+            .take_while(|grapheme| {
+                let chars = grapheme.chars().count();
+                let fits = chars <= room;
+                room = room.saturating_sub(chars);
+                fits
+            })
+            .collect();
+        (!fitted.is_empty()).then_some(Edit::Insert { at, text: fitted })
+    }
+
+    /// The edit a backspace at the end of `text` makes: deleting its last grapheme cluster.
+    ///
+    /// Returns `None` if the text is empty.
+    pub fn backspace(text: &str) -> Option<Edit> {
+        let last = text.graphemes(true).next_back()?;
+        let len = last.chars().count();
+        let at = text.chars().count() - len;
+        Some(Edit::Delete {
+            at: u32::try_from(at).unwrap_or(u32::MAX),
+            len: u32::try_from(len).unwrap_or(u32::MAX),
+        })
+    }
+}
+
+/// Widen the byte range `start..end` of `text` to the grapheme clusters it touches
+fn grapheme_bounds(text: &str, start: usize, end: usize) -> (usize, usize) {
+    let mut bounds = (0, text.len());
+    for (offset, grapheme) in text.grapheme_indices(true) {
+        if offset <= start {
+            bounds.0 = offset;
+        }
+        if offset + grapheme.len() >= end {
+            bounds.1 = offset + grapheme.len();
+            break;
+        }
+    }
+    bounds
+}
+
+/// The byte offset of the char at `chars`, or the end of the text if it is shorter
+fn byte_offset(text: &str, chars: u32) -> usize {
+    text.char_indices()
+        .nth(chars as usize)
+        .map_or(text.len(), |(offset, _)| offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(text: &str, edits: &[Edit]) -> String {
+        let mut text = String::from(text);
+        for edit in edits {
+            edit.apply(&mut text);
+        }
+        text
+    }
+
+    #[test]
+    fn apply() {
+        let insert = |at, text: &str| Edit::Insert {
+            at,
+            text: String::from(text),
+        };
+        assert_eq!(applied("", &[insert(0, "hi")]), "hi");
+        assert_eq!(applied("hllo", &[insert(1, "e")]), "hello");
+        assert_eq!(applied("你好", &[insert(1, "们")]), "你们好");
+        assert_eq!(applied("🫠", &[insert(1, "!")]), "🫠!");
+        assert_eq!(applied("hello", &[Edit::Delete { at: 1, len: 3 }]), "ho");
+        assert_eq!(applied("a🫠b", &[Edit::Delete { at: 1, len: 1 }]), "ab");
+    }
+
+    #[test]
+    fn apply_clamps() {
+        let insert = Edit::Insert {
+            at: 99,
+            text: String::from("!"),
+        };
+        assert_eq!(applied("hi", &[insert]), "hi!");
+        assert_eq!(applied("hi", &[Edit::Delete { at: 1, len: 99 }]), "h");
+        assert_eq!(applied("hi", &[Edit::Delete { at: 99, len: 1 }]), "hi");
+        assert_eq!(
+            applied(
+                "hi",
+                &[Edit::Delete {
+                    at: 1,
+                    len: u32::MAX
+                }]
+            ),
+            "h"
+        );
+    }
+
+    #[test]
+    fn diff() {
+        let cases = [
+            ("", ""),
+            ("", "h"),
+            ("h", "hi"),
+            ("hi", "h"),
+            ("hello", "help"),
+            ("hello", "jello"),
+            ("hello world", "hello, world"),
+            ("hello world", "world"),
+            ("aaa", "aa"),
+            ("abc", "xyz"),
+            ("naïve 🫠", "naive 🫠🫠"),
+            ("你们随便玩儿", "你们玩儿"),
+        ];
+        for (old, new) in cases {
+            let edits = Edit::diff(old, new);
+            assert_eq!(applied(old, &edits), new, "{old:?} -> {new:?}: {edits:?}");
+            assert!(edits.len() <= 2);
+        }
+        assert!(Edit::diff("same", "same").is_empty());
+        assert_eq!(
+            Edit::diff("hllo", "hello"),
+            [Edit::Insert {
+                at: 1,
+                text: String::from("e")
+            }]
+        );
+        assert_eq!(Edit::diff("hello", "ho"), [Edit::Delete { at: 1, len: 3 }]);
+    }
+
+    const FAMILY: &str = "👨\u{200d}👩\u{200d}👧\u{200d}👦";
+    const FLAGS: &str = "🇫🇷🇯🇵";
+    const E_ACUTE: &str = "e\u{301}";
+
+    #[test]
+    fn delete_graphemes() {
+        // deleting any char of a cluster deletes all of it
+        assert_eq!(applied(FAMILY, &[Edit::Delete { at: 6, len: 1 }]), "");
+        assert_eq!(applied(FLAGS, &[Edit::Delete { at: 1, len: 1 }]), "🇯🇵");
+        assert_eq!(applied(FLAGS, &[Edit::Delete { at: 2, len: 1 }]), "🇫🇷");
+        let cafe = format!("caf{E_ACUTE}s");
+        assert_eq!(applied(&cafe, &[Edit::Delete { at: 4, len: 1 }]), "cafs");
+        assert_eq!(applied("café", &[Edit::Delete { at: 3, len: 1 }]), "caf");
+        assert_eq!(applied(FAMILY, &[Edit::Delete { at: 2, len: 0 }]), FAMILY);
+    }
+
+    #[test]
+    fn diff_graphemes() {
+        let cases = [
+            (format!("hi {FAMILY}"), String::from("hi ")),
+            (format!("{FAMILY}{FAMILY}"), String::from(FAMILY)),
+            // the family losing a member is a different family, not a shorter one
+            (String::from(FAMILY), String::from("👨\u{200d}👩\u{200d}👧")),
+            (String::from(FLAGS), String::from("🇫🇷")),
+            (String::from(FLAGS), String::from("🇯🇵")),
+            (String::from("🇫🇷🇷🇺"), String::from("🇷🇺")),
+            (String::from("e"), String::from(E_ACUTE)),
+            (format!("caf{E_ACUTE}"), String::from("caf")),
+        ];
+        for (old, new) in cases {
+            let edits = Edit::diff(&old, &new);
+            assert_eq!(applied(&old, &edits), new, "{old:?} -> {new:?}: {edits:?}");
+        }
+        assert_eq!(Edit::diff("🇫🇷🇷🇺", "🇷🇺"), [Edit::Delete { at: 0, len: 2 }]);
+        assert_eq!(
+            Edit::diff("e", E_ACUTE),
+            [
+                Edit::Delete { at: 0, len: 1 },
+                Edit::Insert {
+                    at: 0,
+                    text: String::from(E_ACUTE)
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn backspace() {
+        assert_eq!(Edit::backspace(""), None);
+        assert_eq!(
+            Edit::backspace(&format!("hi{FAMILY}")),
+            Some(Edit::Delete { at: 2, len: 7 })
+        );
+        assert_eq!(Edit::backspace(FLAGS), Some(Edit::Delete { at: 2, len: 2 }));
+        assert_eq!(
+            Edit::backspace(&format!("caf{E_ACUTE}")),
+            Some(Edit::Delete { at: 3, len: 2 })
+        );
+        assert_eq!(Edit::backspace("🫠"), Some(Edit::Delete { at: 0, len: 1 }));
+    }
+
+    #[test]
+    fn fit() {
+        let insert = |at, text: &str| Edit::Insert {
+            at,
+            text: String::from(text),
+        };
+        assert_eq!(insert(0, "hello").fit("", 10), Some(insert(0, "hello")));
+        assert_eq!(insert(2, "hello").fit("abc", 5), Some(insert(2, "he")));
+        assert_eq!(insert(0, "x").fit("abc", 3), None);
+        assert_eq!(insert(0, "x").fit("abcd", 3), None);
+        // a family doesn't fit in two chars
+        assert_eq!(
+            insert(0, &format!("a{FAMILY}")).fit("", 2),
+            Some(insert(0, "a"))
+        );
+        assert_eq!(insert(0, FLAGS).fit("", 3), Some(insert(0, "🇫🇷")));
+        let delete = Edit::Delete { at: 0, len: 1 };
+        assert_eq!(delete.clone().fit("abcd", 3), Some(delete));
+    }
+}
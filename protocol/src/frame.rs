@@ -0,0 +1,364 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Binary keystroke frames for `/api/ws/key`
+//!
+//! The same encodings are carried by `/api/ws`, behind a frame type (see the `socket` module).
+//!
+//! Every frame carries an `Edit`, encoded as a 9 byte header and then any text:
+//! first byte = the op: 0 for insert, 1 for delete
+//! next 4 bytes = little endian char offset `at`
+//! next 4 bytes = little endian length: of the inserted text in UTF-8 bytes, or of the deleted
+//! range in chars
+//! rest = the inserted text, as UTF-8
+//!
+//! Client -> server frames are one or more edits, back to back, applied in order. A paste or a
+//! finished IME composition that replaces a selection is a delete and an insert in one frame. The
+//! server already knows which message the session is typing, so no message id is sent.
+//!
+//! Server -> client frames put 8 bytes in front of the edit:
+//! first 4 bytes = little endian message id
+//! next 4 bytes = little endian milliseconds since the message was created
+
+use crate::{Edit, Keystroke};
+use std::fmt;
+use std::time::Duration;
+
+/// Length of an encoded edit, not counting inserted text
+pub const EDIT_HEADER_LEN: usize = 9;
+/// Length of the message id and time in front of the edit in a server -> client keystroke frame
+pub const KEYSTROKE_HEADER_LEN: usize = 8;
+
+const OP_INSERT: u8 = 0;
+const OP_DELETE: u8 = 1;
+
+/// Why a binary frame could not be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame was not the expected number of bytes
+    Length { expected: usize, actual: usize },
+    /// The edit op is not one this version knows
+    UnknownOp(u8),
+    /// The inserted text is not valid UTF-8
+    InvalidText,
+    /// The frame type on an `/api/ws` frame is not one this version knows
+    UnknownType(u8),
+    /// An event on an `/api/ws` frame is not valid JSON for an `Event`
+    InvalidEvent,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Length { expected, actual } => {
+                write!(f, "expected a {expected} byte frame, got {actual} bytes")
+            }
+            FrameError::UnknownOp(op) => write!(f, "unknown edit op {op}"),
+            FrameError::InvalidText => write!(f, "inserted text is not valid UTF-8"),
+            FrameError::UnknownType(frame_type) => write!(f, "unknown frame type {frame_type}"),
+            FrameError::InvalidEvent => write!(f, "event is not valid JSON"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Encode an edit typed by the client
+pub fn encode_edit(edit: &Edit) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(EDIT_HEADER_LEN);
+    write_edit(&mut buffer, edit);
+    buffer
+}
+
+/// Encode a batch of edits typed by the client
+pub fn encode_edits(edits: &[Edit]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(EDIT_HEADER_LEN * edits.len());
+    for edit in edits {
+        write_edit(&mut buffer, edit);
+    }
+    buffer
+}
+
+/// Decode a batch of edits typed by the client. There is always at least one.
+pub fn decode_edits(mut bytes: &[u8]) -> Result<Vec<Edit>, FrameError> {
+    let mut edits = Vec::new();
+    loop {
+        let (edit, len) = read_edit(bytes)?;
+        edits.push(edit);
+        bytes = &bytes[len..];
+        if bytes.is_empty() {
+            return Ok(edits);
+        }
+    }
+}
+
+/// Decode a single edit
+pub fn decode_edit(bytes: &[u8]) -> Result<Edit, FrameError> {
+    let (edit, len) = read_edit(bytes)?;
+    if len != bytes.len() {
+        return Err(FrameError::Length {
+            expected: len,
+            actual: bytes.len(),
+        });
+    }
+    Ok(edit)
+}
+
+fn write_edit(buffer: &mut Vec<u8>, edit: &Edit) {
+    let (op, at, len, text) = match edit {
+        // the length is u32, so an insert over 4GiB can't be encoded. Nothing can type that.
+        Edit::Insert { at, text } => (OP_INSERT, at, text.len() as u32, text.as_bytes()),
+        Edit::Delete { at, len } => (OP_DELETE, at, *len, &[][..]),
+    };
+    buffer.push(op);
+    buffer.extend_from_slice(&at.to_le_bytes());
+    buffer.extend_from_slice(&len.to_le_bytes());
+    buffer.extend_from_slice(text);
+}
+
+/// Read the edit at the start of `bytes`, and how many bytes it took up
+fn read_edit(bytes: &[u8]) -> Result<(Edit, usize), FrameError> {
+    if bytes.len() < EDIT_HEADER_LEN {
+        return Err(FrameError::Length {
+            expected: EDIT_HEADER_LEN,
+            actual: bytes.len(),
+        });
+    }
+    let at = u32_from_le(&bytes[1..5]);
+    let len = u32_from_le(&bytes[5..9]);
+    match bytes[0] {
+        OP_INSERT => {
+            let end = EDIT_HEADER_LEN.saturating_add(len as usize);
+            let text = bytes.get(EDIT_HEADER_LEN..end).ok_or(FrameError::Length {
+                expected: end,
+                actual: bytes.len(),
+            })?;
+            let text = std::str::from_utf8(text).map_err(|_| FrameError::InvalidText)?;
+            let text = String::from(text);
+            Ok((Edit::Insert { at, text }, end))
+        }
+        OP_DELETE => Ok((Edit::Delete { at, len }, EDIT_HEADER_LEN)),
+        op => Err(FrameError::UnknownOp(op)),
+    }
+}
+
+pub(crate) fn u32_from_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+impl Keystroke {
+    /// Encode a keystroke relayed from the server
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(KEYSTROKE_HEADER_LEN + EDIT_HEADER_LEN);
+        buffer.extend_from_slice(&self.message_id.to_le_bytes());
+        // a u32 of milliseconds is ~49 days, which is plenty for one message
+        let millis = u32::try_from(self.time.as_millis()).unwrap_or(u32::MAX);
+        buffer.extend_from_slice(&millis.to_le_bytes());
+        write_edit(&mut buffer, &self.edit);
+        buffer
+    }
+
+    /// Decode a keystroke relayed from the server
+    pub fn decode(bytes: &[u8]) -> Result<Self, FrameError> {
+        if bytes.len() < KEYSTROKE_HEADER_LEN {
+            return Err(FrameError::Length {
+                expected: KEYSTROKE_HEADER_LEN + EDIT_HEADER_LEN,
+                actual: bytes.len(),
+            });
+        }
+        let message_id = u32_from_le(&bytes[0..4]);
+        let millis = u32_from_le(&bytes[4..8]);
+        let edit = decode_edit(&bytes[KEYSTROKE_HEADER_LEN..]).map_err(|e| match e {
+            // report lengths of the whole frame
+            FrameError::Length { expected, actual } => FrameError::Length {
+                expected: expected + KEYSTROKE_HEADER_LEN,
+                actual: actual + KEYSTROKE_HEADER_LEN,
+            },
+            e => e,
+        })?;
+        Ok(Keystroke {
+            message_id,
+            edit,
+            time: Duration::from_millis(millis.into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edits() -> Vec<Edit> {
+        let mut edits: Vec<Edit> = ["", "a", "\x08", " ", "é", "你好", "🫠", "hello 🫠 你们"]
+            .into_iter()
+            .enumerate()
+            .map(|(at, text)| Edit::Insert {
+                at: at as u32,
+                text: String::from(text),
+            })
+            .collect();
+        edits.push(Edit::Delete { at: 0, len: 1 });
+        edits.push(Edit::Delete {
+            at: u32::MAX,
+            len: u32::MAX,
+        });
+        edits
+    }
+
+    #[test]
+    fn edit_round_trip() {
+        for edit in edits() {
+            assert_eq!(decode_edit(&encode_edit(&edit)), Ok(edit));
+        }
+    }
+
+    #[test]
+    fn edits_round_trip() {
+        let edits = edits();
+        assert_eq!(decode_edits(&encode_edits(&edits)), Ok(edits.clone()));
+        for edit in edits {
+            assert_eq!(decode_edits(&encode_edit(&edit)), Ok(vec![edit]));
+        }
+    }
+
+    #[test]
+    fn bad_batch() {
+        assert_eq!(
+            decode_edits(&[]),
+            Err(FrameError::Length {
+                expected: EDIT_HEADER_LEN,
+                actual: 0
+            })
+        );
+        // a whole delete, then half of one
+        let mut bytes = encode_edit(&Edit::Delete { at: 0, len: 1 });
+        bytes.extend_from_slice(&[1, 0, 0]);
+        assert_eq!(
+            decode_edits(&bytes),
+            Err(FrameError::Length {
+                expected: EDIT_HEADER_LEN,
+                actual: 3
+            })
+        );
+    }
+
+    #[test]
+    fn keystroke_round_trip() {
+        let times = [0, 1, 999, 1_000, 86_400_000, u32::MAX.into()].map(Duration::from_millis);
+        let message_ids = [0, 1, 256, u32::MAX].into_iter().cycle();
+        for ((edit, time), message_id) in edits()
+            .into_iter()
+            .zip(times.iter().cycle())
+            .zip(message_ids)
+        {
+            let keystroke = Keystroke {
+                message_id,
+                edit,
+                time: *time,
+            };
+            assert_eq!(Keystroke::decode(&keystroke.encode()), Ok(keystroke));
+        }
+    }
+
+    #[test]
+    fn edit_layout() {
+        let insert = Edit::Insert {
+            at: 0x0102,
+            text: String::from("🫠"),
+        };
+        assert_eq!(
+            encode_edit(&insert),
+            [0, 0x02, 0x01, 0, 0, 4, 0, 0, 0, 0xf0, 0x9f, 0xab, 0xa0]
+        );
+        let delete = Edit::Delete { at: 3, len: 0x0a0b };
+        assert_eq!(encode_edit(&delete), [1, 3, 0, 0, 0, 0x0b, 0x0a, 0, 0]);
+    }
+
+    #[test]
+    fn keystroke_layout() {
+        let keystroke = Keystroke {
+            message_id: 0x0102_0304,
+            edit: Edit::Delete { at: 5, len: 1 },
+            time: Duration::from_millis(0x0a0b),
+        };
+        assert_eq!(
+            keystroke.encode(),
+            [
+                0x04, 0x03, 0x02, 0x01, 0x0b, 0x0a, 0x00, 0x00, 1, 5, 0, 0, 0, 1, 0, 0, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn keystroke_time_truncates_to_millis() {
+        let keystroke = Keystroke {
+            message_id: 3,
+            edit: Edit::Delete { at: 0, len: 1 },
+            time: Duration::from_micros(1_500),
+        };
+        let decoded = Keystroke::decode(&keystroke.encode()).unwrap();
+        assert_eq!(decoded.time, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn bad_length() {
+        assert_eq!(
+            decode_edit(&[1, 0, 0]),
+            Err(FrameError::Length {
+                expected: EDIT_HEADER_LEN,
+                actual: 3
+            })
+        );
+        // says it inserts 4 bytes, but only has 1
+        assert_eq!(
+            decode_edit(&[0, 0, 0, 0, 0, 4, 0, 0, 0, 0x61]),
+            Err(FrameError::Length {
+                expected: 13,
+                actual: 10
+            })
+        );
+        // trailing bytes after a delete
+        assert_eq!(
+            decode_edit(&[1, 0, 0, 0, 0, 1, 0, 0, 0, 0]),
+            Err(FrameError::Length {
+                expected: 9,
+                actual: 10
+            })
+        );
+        assert_eq!(
+            Keystroke::decode(&[0x61, 0, 0, 0, 1, 0, 0, 0]),
+            Err(FrameError::Length {
+                expected: KEYSTROKE_HEADER_LEN + EDIT_HEADER_LEN,
+                actual: 8
+            })
+        );
+    }
+
+    #[test]
+    fn bad_edit() {
+        assert_eq!(
+            decode_edit(&[7, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(FrameError::UnknownOp(7))
+        );
+        // a lone continuation byte
+        assert_eq!(
+            decode_edit(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0x80]),
+            Err(FrameError::InvalidText)
+        );
+    }
+}
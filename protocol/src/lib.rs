@@ -0,0 +1,314 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Types shared by the cavalier backend and frontend
+//!
+//! Everything that crosses the wire lives here, so the server and the wasm client can't drift
+//! apart silently:
+//! 1. `Message`, `Author`, `RawMessage`, `Keystroke`, `Event`, `Presence` and `RoomInfo`, which
+//!    are sent as JSON
+//! 2. `Edit`s, the changes a keystroke makes to a message, and how to apply them (see the `edit`
+//!    module)
+//! 3. The binary keystroke frames sent over `/api/ws/key` (see the `frame` module)
+//! 4. The tagged frames carrying events, keystrokes and acks over `/api/ws` (see the `socket`
+//!    module)
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+pub mod edit;
+pub mod frame;
+pub mod socket;
+
+pub use edit::Edit;
+pub use frame::FrameError;
+pub use socket::{ClientFrame, ServerFrame};
+
+/// A Message.
+///
+/// The text is rendered: it is what every edit typed into the message adds up to, worked out once
+/// by the server, so clients show it as is. The keystrokes that typed it are kept in a separate,
+/// append-only log; fetch them as a `RawMessage`, or from the message's replay to play it back.
+/// A message is `finished` once its author sends it, leaves, or stops typing for too long. After
+/// that it never changes again.
+/// The `author` is who the message was typed by, as they were named when they started it. The
+/// welcome message, and messages typed before authors existed, have none.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub id: u32,
+    pub text: String,
+    #[serde(default)]
+    pub finished: bool,
+    #[serde(default)]
+    pub author: Option<Author>,
+}
+
+/// Who typed a message.
+///
+/// Every session is given a random `pseudonym` the first time it makes a message, which it keeps
+/// for as long as the session lasts. Users can also choose a `nickname` to be shown instead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Author {
+    pub pseudonym: String,
+    #[serde(default)]
+    pub nickname: Option<String>,
+}
+
+impl Author {
+    /// The name to show for the author
+    pub fn display_name(&self) -> &str {
+        self.nickname.as_deref().unwrap_or(&self.pseudonym)
+    }
+
+    /// The hue (0-359) to color the author's name with.
+    ///
+    /// It is a hash of the pseudonym, so every client picks the same color, and it doesn't change
+    /// when the author changes their nickname.
+    pub fn hue(&self) -> u16 {
+        // FNV-1a
+        let hash = self.pseudonym.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+            (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+        });
+        (hash % 360) as u16
+    }
+}
+
+/// The longest allowed nickname, in chars
+pub const NICKNAME_MAX_LEN: usize = 32;
+
+/// Whether `nickname` can be used as a nickname: not blank, no longer than `NICKNAME_MAX_LEN`,
+/// without leading or trailing whitespace, and without control characters.
+pub fn valid_nickname(nickname: &str) -> bool {
+    !nickname.is_empty()
+        && nickname.trim() == nickname
+        && nickname.chars().count() <= NICKNAME_MAX_LEN
+        && !nickname.chars().any(char::is_control)
+}
+
+/// A message along with the keystroke log it was rendered from, as sent by `/msg/get?raw=true`.
+///
+/// The message's fields are flattened into the same JSON object as `keystrokes`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RawMessage {
+    #[serde(flatten)]
+    pub message: Message,
+    pub keystrokes: Vec<Keystroke>,
+}
+
+/// A keystroke
+///
+/// Associates an edit with message_id, timing, any other info.
+/// `time` is the offset from when the message was created, which is what playback needs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Keystroke {
+    pub message_id: u32,
+    pub edit: Edit,
+    pub time: Duration,
+}
+
+/// An event
+///
+/// Communicates from the server to the client that a new message has been created, a message is
+/// over, there is a new user, etc any live updates the client could want
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", content = "data")]
+pub enum Event {
+    MessageNew(Message),
+    MessageEnd {
+        id: u32,
+    },
+    /// The server dropped an old message to make room for new ones. Clients drop it too.
+    MessageEvicted {
+        id: u32,
+    },
+    /// The client fell behind and missed updates. These messages replace whatever it has for them.
+    ///
+    /// Sent over whichever socket fell behind. The legacy sockets send it as text, even the key
+    /// socket.
+    Resync {
+        messages: Vec<Message>,
+    },
+    /// A user came into the room. Sent once per session, however many tabs it has open.
+    UserJoined(Author),
+    /// A user's last connection to the room closed
+    UserLeft(Author),
+    /// How many users are in the room, sent whenever it changes
+    Online {
+        count: u32,
+    },
+    /// The server is going away, and every message in the room has been finished. It is the last
+    /// event before the socket closes; clients reconnect once the server is back.
+    ServerShuttingDown,
+}
+
+/// Who is in a room, as sent by `/api/presence`. Kept up to date by `Event::UserJoined`,
+/// `Event::UserLeft` and `Event::Online`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Presence {
+    pub users: Vec<Author>,
+}
+
+/// The name of the room used by the routes that don't name one
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// The longest allowed room name, in bytes
+pub const ROOM_NAME_MAX_LEN: usize = 32;
+
+/// A chat room, as listed and created by `/api/rooms`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RoomInfo {
+    pub name: String,
+}
+
+/// Whether `name` can be used as a room name.
+///
+/// Room names end up in URL paths, so they are limited to lowercase ascii letters, digits, `-` and
+/// `_`.
+pub fn valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= ROOM_NAME_MAX_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_json_round_trip() {
+        let events = [
+            Event::MessageNew(Message {
+                id: 7,
+                text: String::from("hello 🫠"),
+                finished: false,
+                author: Some(Author {
+                    pseudonym: String::from("Brisk Otter 7"),
+                    nickname: Some(String::from("sam")),
+                }),
+            }),
+            Event::MessageEnd { id: 7 },
+            Event::MessageEvicted { id: 1 },
+            Event::UserJoined(Author {
+                pseudonym: String::from("Quiet Heron 3"),
+                nickname: None,
+            }),
+            Event::Online { count: 2 },
+            Event::ServerShuttingDown,
+            Event::Resync {
+                messages: vec![Message {
+                    id: 8,
+                    text: String::from("re"),
+                    finished: true,
+                    author: None,
+                }],
+            },
+        ];
+        for event in events {
+            let json = serde_json::to_string(&event).unwrap();
+            assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn raw_message_json() {
+        let raw = RawMessage {
+            message: Message {
+                id: 3,
+                text: String::from("hi"),
+                finished: true,
+                author: None,
+            },
+            keystrokes: vec![Keystroke {
+                message_id: 3,
+                edit: Edit::Insert {
+                    at: 0,
+                    text: String::from("hi"),
+                },
+                time: Duration::from_millis(20),
+            }],
+        };
+        let json = serde_json::to_value(&raw).unwrap();
+        assert_eq!(json["text"], "hi");
+        assert_eq!(json["keystrokes"][0]["edit"]["op"], "Insert");
+        assert_eq!(serde_json::from_value::<RawMessage>(json).unwrap(), raw);
+    }
+
+    #[test]
+    fn room_names() {
+        assert!(valid_room_name(DEFAULT_ROOM));
+        assert!(valid_room_name("rust-lang_2025"));
+        assert!(valid_room_name(&"a".repeat(ROOM_NAME_MAX_LEN)));
+        assert!(!valid_room_name(""));
+        assert!(!valid_room_name(&"a".repeat(ROOM_NAME_MAX_LEN + 1)));
+        assert!(!valid_room_name("Lobby"));
+        assert!(!valid_room_name("a/b"));
+        assert!(!valid_room_name("a b"));
+        assert!(!valid_room_name("café"));
+    }
+
+    #[test]
+    fn authors() {
+        let mut author = Author {
+            pseudonym: String::from("Quiet Heron 3"),
+            nickname: None,
+        };
+        let hue = author.hue();
+        assert!(hue < 360);
+        assert_eq!(author.display_name(), "Quiet Heron 3");
+        author.nickname = Some(String::from("sam"));
+        assert_eq!(author.display_name(), "sam");
+        assert_eq!(author.hue(), hue);
+        let other = Author {
+            pseudonym: String::from("Brisk Otter 7"),
+            nickname: None,
+        };
+        assert_ne!(other.hue(), hue);
+
+        assert!(valid_nickname("sam"));
+        assert!(valid_nickname("Sam M. 🫠"));
+        assert!(valid_nickname(&"你".repeat(NICKNAME_MAX_LEN)));
+        assert!(!valid_nickname(""));
+        assert!(!valid_nickname(" sam"));
+        assert!(!valid_nickname("sam\n"));
+        assert!(!valid_nickname("s\x08am"));
+        assert!(!valid_nickname(&"a".repeat(NICKNAME_MAX_LEN + 1)));
+    }
+
+    #[test]
+    fn event_json_shape() {
+        let event = Event::MessageNew(Message {
+            id: 1,
+            text: String::from("a"),
+            finished: false,
+            author: Some(Author {
+                pseudonym: String::from("Quiet Heron 3"),
+                nickname: None,
+            }),
+        });
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"MessageNew","data":{"id":1,"text":"a","finished":false,"author":{"pseudonym":"Quiet Heron 3","nickname":null}}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Event::MessageEnd { id: 1 }).unwrap(),
+            r#"{"event":"MessageEnd","data":{"id":1}}"#
+        );
+    }
+}
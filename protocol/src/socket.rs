@@ -0,0 +1,284 @@
+/**************************************************************************\
+* Cavalier: extralive chat                                                 *
+* Copyright (C) 2025 Samuel A. Mansfield                                   *
+*                                                                          *
+* This program is free software: you can redistribute it and/or modify     *
+* it under the terms of the GNU Affero General Public License as           *
+* published by the Free Software Foundation, either version 3 of the       *
+* License, or (at your option) any later version.                          *
+*                                                                          *
+* This program is distributed in the hope that it will be useful,          *
+* but WITHOUT ANY WARRANTY; without even the implied warranty of           *
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the            *
+* GNU Affero General Public License for more details.                      *
+*                                                                          *
+* You should have received a copy of the GNU Affero General Public License *
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.   *
+\**************************************************************************/
+
+//! Frames on the `/api/ws` socket
+//!
+//! One socket carries both events and keystrokes, so a client sees them in the order the room
+//! sent them: a message's `MessageNew` always comes before its first keystroke. Every frame is
+//! binary, and its first byte says what the rest is:
+//!
+//! Server -> client:
+//! 0 = an `Event`, as JSON
+//! 1 = a keystroke, encoded as on `/api/ws/key` (see the `frame` module)
+//! 3 = an ack: the client's little endian 4 byte sequence number
+//!
+//! Client -> server:
+//! 2 = a little endian 4 byte sequence number, then one or more edits, encoded as on `/api/ws/key`
+//!
+//! The types don't overlap between directions, so a frame sent the wrong way is caught as unknown.
+//!
+//! Clients number their batches of edits, so they can show what they typed right away and match
+//! it up with the server later. The server acks every batch once it is done with it, after the
+//! keystrokes it made, so by the time a client sees an ack it has seen the keystrokes too. Clients
+//! that would rather not have their own keystrokes sent back can open the socket with
+//! `?echo=false`; the acks still come.
+
+use crate::frame::{self, FrameError};
+use crate::{Edit, Event, Keystroke};
+
+const TYPE_EVENT: u8 = 0;
+const TYPE_KEYSTROKE: u8 = 1;
+const TYPE_EDITS: u8 = 2;
+const TYPE_ACK: u8 = 3;
+
+/// Length of a sequence number
+pub const SEQ_LEN: usize = 4;
+
+/// A frame sent from the server to the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerFrame {
+    Event(Event),
+    Keystroke(Keystroke),
+    /// The server is done with the client's batch of edits numbered `seq`
+    Ack {
+        seq: u32,
+    },
+}
+
+/// A frame sent from the client to the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientFrame {
+    /// Edits to the message the session is typing, applied in order, numbered for the ack
+    Edits { seq: u32, edits: Vec<Edit> },
+}
+
+impl ServerFrame {
+    /// Encode a frame for the client
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            ServerFrame::Event(event) => {
+                let mut buffer = vec![TYPE_EVENT];
+                // an event is plain data, so it always serializes
+                serde_json::to_writer(&mut buffer, event).unwrap();
+                buffer
+            }
+            ServerFrame::Keystroke(keystroke) => {
+                let mut buffer = vec![TYPE_KEYSTROKE];
+                buffer.extend_from_slice(&keystroke.encode());
+                buffer
+            }
+            ServerFrame::Ack { seq } => {
+                let mut buffer = vec![TYPE_ACK];
+                buffer.extend_from_slice(&seq.to_le_bytes());
+                buffer
+            }
+        }
+    }
+
+    /// Decode a frame from the server
+    pub fn decode(bytes: &[u8]) -> Result<Self, FrameError> {
+        let (frame_type, rest) = split_type(bytes)?;
+        match frame_type {
+            TYPE_EVENT => serde_json::from_slice(rest)
+                .map(ServerFrame::Event)
+                .map_err(|_| FrameError::InvalidEvent),
+            TYPE_KEYSTROKE => Keystroke::decode(rest).map(ServerFrame::Keystroke),
+            TYPE_ACK => {
+                let (seq, rest) = split_seq(rest)?;
+                if !rest.is_empty() {
+                    return Err(FrameError::Length {
+                        expected: SEQ_LEN,
+                        actual: SEQ_LEN + rest.len(),
+                    });
+                }
+                Ok(ServerFrame::Ack { seq })
+            }
+            frame_type => Err(FrameError::UnknownType(frame_type)),
+        }
+    }
+}
+
+impl ClientFrame {
+    /// Encode a frame for the server
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            ClientFrame::Edits { seq, edits } => {
+                let mut buffer = vec![TYPE_EDITS];
+                buffer.extend_from_slice(&seq.to_le_bytes());
+                buffer.extend_from_slice(&frame::encode_edits(edits));
+                buffer
+            }
+        }
+    }
+
+    /// Decode a frame from the client
+    pub fn decode(bytes: &[u8]) -> Result<Self, FrameError> {
+        let (frame_type, rest) = split_type(bytes)?;
+        match frame_type {
+            TYPE_EDITS => {
+                let (seq, rest) = split_seq(rest)?;
+                let edits = frame::decode_edits(rest)?;
+                Ok(ClientFrame::Edits { seq, edits })
+            }
+            frame_type => Err(FrameError::UnknownType(frame_type)),
+        }
+    }
+}
+
+/// Split the frame type off the front of a frame
+fn split_type(bytes: &[u8]) -> Result<(u8, &[u8]), FrameError> {
+    match bytes.split_first() {
+        Some((frame_type, rest)) => Ok((*frame_type, rest)),
+        None => Err(FrameError::Length {
+            expected: 1,
+            actual: 0,
+        }),
+    }
+}
+
+/// Split the sequence number off the front of a frame's contents
+fn split_seq(bytes: &[u8]) -> Result<(u32, &[u8]), FrameError> {
+    if bytes.len() < SEQ_LEN {
+        return Err(FrameError::Length {
+            expected: SEQ_LEN,
+            actual: bytes.len(),
+        });
+    }
+    let (seq, rest) = bytes.split_at(SEQ_LEN);
+    Ok((frame::u32_from_le(seq), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+    use std::time::Duration;
+
+    #[test]
+    fn server_frames_round_trip() {
+        let frames = [
+            ServerFrame::Event(Event::MessageNew(Message {
+                id: 4,
+                text: String::from("hi 🫠"),
+                finished: false,
+                author: None,
+            })),
+            ServerFrame::Event(Event::ServerShuttingDown),
+            ServerFrame::Keystroke(Keystroke {
+                message_id: 4,
+                edit: Edit::Insert {
+                    at: 5,
+                    text: String::from("你"),
+                },
+                time: Duration::from_millis(250),
+            }),
+            ServerFrame::Ack { seq: 0 },
+            ServerFrame::Ack { seq: u32::MAX },
+        ];
+        for frame in frames {
+            assert_eq!(ServerFrame::decode(&frame.encode()), Ok(frame));
+        }
+    }
+
+    #[test]
+    fn client_frames_round_trip() {
+        let frame = ClientFrame::Edits {
+            seq: 7,
+            edits: vec![
+                Edit::Delete { at: 0, len: 2 },
+                Edit::Insert {
+                    at: 0,
+                    text: String::from("é"),
+                },
+            ],
+        };
+        assert_eq!(ClientFrame::decode(&frame.encode()), Ok(frame));
+    }
+
+    #[test]
+    fn frame_layout() {
+        let event = ServerFrame::Event(Event::MessageEnd { id: 1 }).encode();
+        assert_eq!(event[0], 0);
+        assert_eq!(&event[1..], br#"{"event":"MessageEnd","data":{"id":1}}"#);
+        let keystroke = Keystroke {
+            message_id: 1,
+            edit: Edit::Delete { at: 0, len: 1 },
+            time: Duration::ZERO,
+        };
+        let mut expected = vec![1];
+        expected.extend_from_slice(&keystroke.encode());
+        assert_eq!(ServerFrame::Keystroke(keystroke).encode(), expected);
+        let edits = ClientFrame::Edits {
+            seq: 0x0102,
+            edits: vec![Edit::Delete { at: 3, len: 1 }],
+        }
+        .encode();
+        assert_eq!(edits, [2, 2, 1, 0, 0, 1, 3, 0, 0, 0, 1, 0, 0, 0]);
+        let ack = ServerFrame::Ack { seq: 0x0a0b0c0d }.encode();
+        assert_eq!(ack, [3, 0x0d, 0x0c, 0x0b, 0x0a]);
+    }
+
+    #[test]
+    fn bad_frames() {
+        assert_eq!(
+            ServerFrame::decode(&[]),
+            Err(FrameError::Length {
+                expected: 1,
+                actual: 0
+            })
+        );
+        assert_eq!(ServerFrame::decode(&[9]), Err(FrameError::UnknownType(9)));
+        assert_eq!(
+            ServerFrame::decode(b"\x00{\"event\":\"Nope\"}"),
+            Err(FrameError::InvalidEvent)
+        );
+        // edits are only sent by clients, and events and acks only by the server
+        let edits = ClientFrame::Edits {
+            seq: 1,
+            edits: vec![Edit::Delete { at: 0, len: 1 }],
+        }
+        .encode();
+        assert_eq!(ServerFrame::decode(&edits), Err(FrameError::UnknownType(2)));
+        let event = ServerFrame::Event(Event::Online { count: 1 }).encode();
+        assert_eq!(ClientFrame::decode(&event), Err(FrameError::UnknownType(0)));
+        let ack = ServerFrame::Ack { seq: 1 }.encode();
+        assert_eq!(ClientFrame::decode(&ack), Err(FrameError::UnknownType(3)));
+        // no sequence number, then a sequence number and no edits
+        assert_eq!(
+            ClientFrame::decode(&[2, 1]),
+            Err(FrameError::Length {
+                expected: SEQ_LEN,
+                actual: 1
+            })
+        );
+        assert_eq!(
+            ClientFrame::decode(&[2, 1, 0, 0, 0]),
+            Err(FrameError::Length {
+                expected: frame::EDIT_HEADER_LEN,
+                actual: 0
+            })
+        );
+        assert_eq!(
+            ServerFrame::decode(&[3, 1, 0, 0, 0, 0]),
+            Err(FrameError::Length {
+                expected: SEQ_LEN,
+                actual: SEQ_LEN + 1
+            })
+        );
+    }
+}